@@ -1,25 +1,432 @@
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use std::fs;
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
 
 use serde_json;
 
+use diagnostic::{Code,Diagnostic,Errors};
 use grammar::*;
 use util::read_to_string;
 
 pub struct Compiler {
-    cursor: u16,
+    /// Location counter, which reaches 0x10000 once the last byte of memory is written.
+    cursor: u32,
     output: [u8; 0x10000],
+    sections: Vec<Section>,
+    /// Index of the section being assembled into.
+    section: usize,
+    /// Location counter at the start of the current line, the value of `$`.
+    line_start: u16,
+    /// Open `phase` block, if any.
+    phase: Option<Phase>,
+    /// Output position and section of labels defined inside a `phase` block.
+    load_addresses: HashMap<Label, (u16, usize)>,
+    /// Names and addresses of the vectors, in the order they are written.
+    vector_table: Vec<(String, u16)>,
+    /// Handlers assigned by `vector`, by vector name.
+    vectors: HashMap<String, (Expr, Location)>,
     label_map: HashMap<Label, u16>,
-    needs_label: Vec<(u16, Label, Nibble)>,
+    /// Labels in sections without a base, whose addresses are relative until the sections are placed.
+    relocatable_labels: HashMap<Label, usize>,
+    /// Number of definitions of each numeric label so far.
+    numeric_labels: HashMap<Label, usize>,
+    constants: HashMap<Label, Constant>,
+    needs_label: Vec<Fixup>,
     last_major_label: Label,
     enabled_instructions: Option<HashMap<Opcode, String>>,
     file_stack: FileStack,
+    location: Location,
+    macros: HashMap<String, Macro>,
+    recording: Option<Recording>,
+    expansion_count: usize,
+    conditions: Vec<Condition>,
+    aliases: Aliases,
+    charmap: Charmap,
+    warnings: Vec<Diagnostic>,
+    errors: Vec<Diagnostic>,
+    max_errors: Option<usize>,
+    scopes: Vec<Scope>,
+    no_major_labels: bool,
+    structure: Option<Structure>,
+    assertions: Vec<Assertion>,
+}
+
+/// Settings of a single compilation.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub whitelist: Option<Vec<String>>,
+    /// Don't start a new scope for local labels at labels beginning with an uppercase letter.
+    pub no_major_labels: bool,
+    /// Directories searched for included files that aren't found next to the including file.
+    pub include_paths: Vec<String>,
+    /// Symbols defined before the first line, as `NAME` or `NAME=VALUE`.
+    pub defines: Vec<String>,
+    /// Predefined symbols to remove before the first line.
+    pub undefines: Vec<String>,
+    /// Names and addresses of the CPU's vectors, if different from `DEFAULT_VECTORS`.
+    pub vectors: Option<Vec<(String, u16)>>,
+    /// Number of errors after which to stop assembling, or `None` to report all of them.
+    pub max_errors: Option<usize>,
+}
+
+/// Returned once the number of errors reaches `Options::max_errors`.
+#[derive(Debug)]
+struct ErrorLimit;
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: String,
+    line: usize,
+    /// Position of the line in the order lines are read, counting included files and macro expansions.
+    index: usize,
+    expanded_from: Option<Box<Expansion>>,
+    /// The `include` directive which brought in the file, unless it's the main file or a macro body.
+    included_from: Option<Box<Location>>,
+}
+
+/// The invocation of a macro which produced a line.
+#[derive(Debug, Clone)]
+struct Expansion {
+    name: String,
+    location: Location,
+}
+
+impl Location {
+    fn new(file: &str, line: usize) -> Self {
+        Self {
+            file: file.to_owned(),
+            line,
+            index: 0,
+            expanded_from: None,
+            included_from: None,
+        }
+    }
+
+    fn error(&self, code: Code, message: String) -> Diagnostic {
+        self.attach(Diagnostic::error(code, message))
+    }
+
+    fn warning(&self, code: Code, message: String) -> Diagnostic {
+        self.attach(Diagnostic::warning(code, message))
+    }
+
+    /// Points `diagnostic` at this location, unless it already points elsewhere.
+    fn attach(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if diagnostic.file.is_some() {
+            return diagnostic;
+        }
+
+        diagnostic.file = Some(self.file.clone());
+        diagnostic.line = Some(self.line);
+        diagnostic.index = Some(self.index);
+
+        let mut outermost = self;
+        while let Some(ref expansion) = outermost.expanded_from {
+            diagnostic.expansions.push((expansion.name.clone(), expansion.location.file.clone(), expansion.location.line));
+            outermost = &expansion.location;
+        }
+
+        let mut include = &outermost.included_from;
+        while let Some(ref location) = *include {
+            diagnostic.include_chain.push((location.file.clone(), location.line));
+            include = &location.included_from;
+        }
+
+        diagnostic
+    }
+
+    /// Describes the chain of macro invocations leading to this location.
+    fn expansion_note(&self) -> String {
+        match self.expanded_from {
+            Some(ref expansion) => format!(" (in '{}' invoked at {}:{}{})",
+                                           expansion.name,
+                                           expansion.location.file,
+                                           expansion.location.line,
+                                           expansion.location.expansion_note()),
+            None => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}{}", self.file, self.line, self.expansion_note())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Size {
+    Byte,
+    Word,
+    WordLittle,
+}
+
+impl Size {
+    /// Checks that `value` fits the operand, accepting both signed and unsigned
+    /// interpretations, and returns its two's complement truncation.
+    fn truncate(&self, value: i64) -> Result<u16, Diagnostic> {
+        let (name, min, max) = match self {
+            Size::Byte => ("a byte", -0x80, 0xFF),
+            Size::Word | Size::WordLittle => ("a word", -0x8000, 0xFFFF),
+        };
+
+        if value < min || value > max {
+            return Err(Diagnostic::error(Code::OutOfRange, format!("value {} is out of range for {} operand", value, name)));
+        }
+
+        Ok((value & max) as u16)
+    }
+}
+
+/// A symbol defined by `equ` or `set` rather than by its position in the output.
+#[derive(Debug)]
+enum Constant {
+    /// Evaluated lazily, so that it may refer to labels defined later.
+    Equ(Expr, Location),
+    /// Evaluated at the point of definition and substituted into every following use.
+    Set(i64),
+    /// Size of a structure defined by `struct`.
+    Struct(i64),
+    /// Offset of a field within a structure.
+    Field(i64),
+    /// Defined before assembly, either built in or on the command line.
+    Predefined(i64),
+}
+
+/// Vectors of the CPU, each holding a jump to its handler.
+const DEFAULT_VECTORS: &[(&str, u16)] = &[
+    ("reset", 0x0000),
+    ("interrupt", 0x0003),
+];
+
+/// Name used with `vector` to assign a handler to every vector without one.
+const DEFAULT_VECTOR: &str = "default";
+
+/// Symbols defined by the assembler itself, which can be removed with `-U`.
+const BUILTIN_SYMBOLS: &[(&str, i64)] = &[
+    ("__ASSEMBLER__", 1),
+];
+
+/// Parses a `NAME[=VALUE]` symbol definition, where the value defaults to 1.
+fn parse_define(define: &str) -> Result<(Label, i64), Diagnostic> {
+    let mut parts = define.splitn(2, '=');
+    let name = parts.next().unwrap();
+
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid || is_reserved(name) {
+        return Err(Diagnostic::error(Code::InvalidOption, format!("Invalid symbol name '{}' in definition '{}'", name, define)));
+    }
+
+    let value = match parts.next() {
+        Some(value) => {
+            let (digits, negative) = match value.strip_prefix('-') {
+                Some(digits) => (digits, true),
+                None => (value, false),
+            };
+            let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            }
+            else if let Some(binary) = digits.strip_prefix("0b") {
+                i64::from_str_radix(binary, 2)
+            }
+            else {
+                digits.parse()
+            };
+            let parsed = parsed.map_err(|_| {
+                Diagnostic::error(Code::InvalidOption, format!("Invalid value '{}' in definition '{}'", value, define))
+            })?;
+            if negative { -parsed } else { parsed }
+        },
+        None => 1,
+    };
+
+    Ok((name.to_owned(), value))
+}
+
+/// Nesting limit for `equ` constants referring to each other, used to detect cycles.
+const MAX_EVALUATION_DEPTH: usize = 64;
+
+/// A block of lines being collected up to its terminating directive.
+#[derive(Debug)]
+struct Recording {
+    block: Block,
+    body: Vec<(usize, String)>,
+    location: Location,
+    /// Number of nested blocks of the same kind opened inside the body.
+    depth: usize,
+}
+
+#[derive(Debug)]
+enum Block {
+    Macro(String, Vec<String>),
+    Rept(i64, Option<Label>),
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(usize, String)>,
+    location: Location,
+}
+
+/// An open `scope` block.
+#[derive(Debug)]
+struct Scope {
+    name: Label,
+    location: Location,
+    /// Major label in effect before the scope was opened.
+    last_major_label: Label,
+}
+
+/// An open `struct` block.
+#[derive(Debug)]
+struct Structure {
+    name: Label,
+    location: Location,
+    /// Offset of the next field.
+    size: i64,
+}
+
+/// An open `phase` block, assembled for a run address different from its output position.
+#[derive(Debug)]
+struct Phase {
+    /// Run address of the start of the block.
+    address: u16,
+    /// Location counter at the start of the block.
+    start: u16,
+    location: Location,
+}
+
+impl Phase {
+    fn run_address(&self, cursor: u16) -> u16 {
+        self.address.wrapping_add(cursor.wrapping_sub(self.start))
+    }
+}
+
+/// Hidden label at the start of a section without a base.
+fn section_start(name: &str) -> Label {
+    format!("{}:{}", HERE, name)
+}
+
+/// A condition checked once all labels are resolved.
+#[derive(Debug)]
+struct Assertion {
+    expr: Expr,
+    message: Option<String>,
+    location: Location,
+}
+
+/// A named part of the output with its own location counter.
+#[derive(Debug)]
+struct Section {
+    name: String,
+    /// Address of the section, or `None` to place it after the preceding section.
+    base: Option<u16>,
+    /// Location counter, relative to the start of the section if it has no base.
+    cursor: u32,
+    /// Location counter past the last byte written.
+    end: u32,
+    /// Largest alignment requested inside the section.
+    alignment: u32,
+    /// Address the section's location counter is relative to, known once placed.
+    offset: u16,
+    image: Vec<Option<u8>>,
+}
+
+impl Section {
+    fn new(name: &str, base: Option<u16>) -> Self {
+        let start = base.unwrap_or(0);
+        Self {
+            name: name.to_owned(),
+            base,
+            cursor: start as u32,
+            end: start as u32,
+            alignment: 1,
+            offset: 0,
+            image: vec![None; 0x10000],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cursor == self.end && self.end == self.base.unwrap_or(0) as u32
+    }
+}
+
+/// An open `if` block.
+#[derive(Debug)]
+struct Condition {
+    location: Location,
+    /// Whether lines in the current branch are assembled.
+    active: bool,
+    /// Whether a branch of this block was already taken, or the enclosing block is inactive.
+    taken: bool,
+    seen_else: bool,
+}
+
+/// Nesting limit for macro invocations, used to detect runaway recursion.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Limit of `rept` counts, enough to fill the whole address space with single bytes.
+const MAX_REPEAT_COUNT: i64 = 0x10000;
+
+/// Checks whether `label` refers to a numeric label, such as `1b` or `2f`.
+fn is_numeric_reference(label: &str) -> bool {
+    label.len() > 1
+        && (label.ends_with("b") || label.ends_with("f"))
+        && label[..label.len() - 1].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Replaces every `\name` in `line` by the corresponding argument, leaving unknown names untouched.
+fn substitute(line: &str, arguments: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+
+    while let Some(i) = rest.find('\\') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@')).unwrap_or(rest.len());
+        match arguments.get(&rest[..len]) {
+            Some(argument) => result.push_str(argument),
+            None => {
+                result.push('\\');
+                continue;
+            },
+        }
+        rest = &rest[len..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// A placeholder in the output that gets filled in by `Compiler::resolve_labels`.
+#[derive(Debug)]
+struct Fixup {
+    section: usize,
+    position: u16,
+    expr: Expr,
+    size: Size,
+    location: Location,
 }
 
 struct FileStack {
     filenames: Vec<String>,
     lines: Vec<Vec<(usize, String)>>,
+    expansions: Vec<Option<Box<Expansion>>>,
+    /// Location of the `include` directive which pushed each file.
+    includes: Vec<Option<Box<Location>>>,
+    search_paths: Vec<PathBuf>,
+    /// Canonical paths of all files included so far.
+    included: HashSet<PathBuf>,
+    /// Canonical paths of files marked with `pragma once`.
+    once: HashSet<PathBuf>,
+    /// Number of lines read so far.
+    read: usize,
 }
 
 impl FileStack {
@@ -27,38 +434,57 @@ impl FileStack {
         Self {
             filenames: Vec::new(),
             lines: Vec::new(),
+            expansions: Vec::new(),
+            includes: Vec::new(),
+            search_paths: Vec::new(),
+            included: HashSet::new(),
+            once: HashSet::new(),
+            read: 0,
         }
     }
 
     fn init(&mut self, file: &str, lines: Vec<(usize, String)>) {
+        if let Ok(canonical) = fs::canonicalize(file) {
+            self.included.insert(canonical);
+        }
         self.filenames.push(file.to_owned());
         self.lines.push(lines);
+        self.expansions.push(None);
+        self.includes.push(None);
     }
 
-    fn push(&mut self, file: &str) -> Result<(), String> {
+    /// Pushes the lines of an included file, unless `once` is set or the file is marked
+    /// with `pragma once` and it was already included.
+    fn push(&mut self, file: &str, once: bool, from: &Location) -> Result<(), Diagnostic> {
         assert!(!self.filenames.is_empty());
         assert_eq!(self.filenames.len(), self.lines.len());
 
-        let filepath: String = {
-            let mut path = PathBuf::from(file);
+        let filepath = self.find(file).map_err(|err| from.attach(err))?;
 
-            if !path.is_file() {
-                return Err(format!("Path '{}' doesn't point to a file.", file));
-            }
+        let canonical = fs::canonicalize(&filepath).ok();
 
-            if !path.is_absolute() {
-                let current_dir = Path::new(self.filenames.last().unwrap()).parent().unwrap();
-                path = current_dir.join(path);
+        if let Some(ref canonical) = canonical {
+            if self.once.contains(canonical) || (once && self.included.contains(canonical)) {
+                return Ok(());
             }
+        }
 
-            path.to_str().unwrap().to_owned()
-        };
+        let included = self.filenames.iter()
+            .zip(self.expansions.iter())
+            .any(|(name, expansion)| expansion.is_none() && match canonical {
+                Some(ref canonical) => fs::canonicalize(name).ok().as_ref() == Some(canonical),
+                None => *name == filepath,
+            });
+
+        if included {
+            return Err(from.error(Code::File, format!("file '{}' is included recursively", filepath)));
+        }
 
-        if self.filenames.contains(&filepath) {
-            return Err(format!("Recursive inclusion detected in file '{}'.", file));
+        if let Some(canonical) = canonical {
+            self.included.insert(canonical);
         }
 
-        let lines = read_to_string(file).split('\n')
+        let lines = read_to_string(&filepath).split('\n')
             .enumerate()
             .map(|(i, x)| (i + 1, x.to_owned()))
             .collect::<Vec<(usize, String)>>().into_iter()
@@ -67,21 +493,99 @@ impl FileStack {
 
         self.filenames.push(filepath);
         self.lines.push(lines);
+        self.expansions.push(None);
+        self.includes.push(Some(Box::new(from.clone())));
+
+        Ok(())
+    }
+
+    /// Looks for a `file` used by `include`, `incbin` or `charmap` next to the current file, then in
+    /// the search paths and finally in the `lib` directory next to the main source file.
+    fn find(&self, file: &str) -> Result<String, Diagnostic> {
+        let path = Path::new(file);
+
+        let candidates = if path.is_absolute() {
+            vec![ path.to_path_buf() ]
+        }
+        else {
+            let mut dirs = vec![ Path::new(self.filenames.last().unwrap()).parent().unwrap().to_path_buf() ];
+            dirs.extend(self.search_paths.iter().cloned());
+            dirs.push(Path::new(&self.filenames[0]).parent().unwrap().join("lib"));
+
+            let mut candidates: Vec<PathBuf> = Vec::new();
+            for dir in dirs {
+                let candidate = dir.join(path);
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                }
+            }
+            candidates
+        };
+
+        match candidates.iter().find(|c| c.is_file()) {
+            Some(found) => Ok(found.to_str().unwrap().to_owned()),
+            None => {
+                let tried = candidates.iter()
+                    .map(|c| format!("'{}'", c.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Err(Diagnostic::error(Code::File, format!("file '{}' not found, tried {}", file, tried)))
+            },
+        }
+    }
+
+    /// Marks the file currently being read so that it's never included again.
+    fn mark_once(&mut self) {
+        let file = self.filenames.iter()
+            .zip(self.expansions.iter())
+            .rev()
+            .find(|&(_, expansion)| expansion.is_none())
+            .map(|(name, _)| name)
+            .unwrap();
+
+        if let Ok(canonical) = fs::canonicalize(file) {
+            self.once.insert(canonical);
+        }
+    }
+
+    /// Pushes the lines of a macro body defined in `file`, in order.
+    fn expand(&mut self, file: &str, lines: Vec<(usize, String)>, expansion: Expansion) -> Result<(), Diagnostic> {
+        let depth = self.expansions.iter().filter(|e| e.is_some()).count();
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(expansion.location.error(Code::Nesting, format!("expansion of '{}' is nested too deeply", expansion.name)));
+        }
+
+        self.filenames.push(file.to_owned());
+        self.lines.push(lines.into_iter().rev().collect());
+        self.expansions.push(Some(Box::new(expansion)));
+        self.includes.push(None);
 
         Ok(())
     }
 
-    fn pop(&mut self) -> Option<(String, (usize, String))> {
+    fn pop(&mut self) -> Option<(Location, String)> {
         if self.filenames.is_empty() {
             None
         }
-        else if let Some(line) = self.lines.last_mut().and_then(|x| x.pop()) {
-            let filename = self.filenames.last_mut().expect("Inconsistent state in FileStack");
-            Some((filename.clone(), line))
+        else if let Some((ln, line)) = self.lines.last_mut().and_then(|x| x.pop()) {
+            let filename = self.filenames.last().expect("Inconsistent state in FileStack");
+            let expansion = self.expansions.last().expect("Inconsistent state in FileStack");
+            let include = self.includes.last().expect("Inconsistent state in FileStack");
+            self.read += 1;
+            let location = Location {
+                file: filename.clone(),
+                line: ln,
+                index: self.read,
+                expanded_from: expansion.clone(),
+                included_from: include.clone(),
+            };
+            Some((location, line))
         }
         else {
             self.filenames.pop();
             self.lines.pop();
+            self.expansions.pop();
+            self.includes.pop();
             self.pop()
         }
     }
@@ -92,333 +596,2186 @@ impl Compiler {
         Self {
             cursor: 0,
             output: [0; 0x10000],
+            sections: vec![ Section::new("code", Some(0)) ],
+            section: 0,
+            line_start: 0,
+            phase: None,
+            load_addresses: HashMap::new(),
+            vector_table: DEFAULT_VECTORS.iter().map(|&(name, address)| (name.to_owned(), address)).collect(),
+            vectors: HashMap::new(),
             label_map: HashMap::new(),
+            relocatable_labels: HashMap::new(),
+            numeric_labels: HashMap::new(),
+            constants: HashMap::new(),
             needs_label: Vec::new(),
             last_major_label: String::new(),
             enabled_instructions: None,
             file_stack: FileStack::new(),
+            location: Location::new("", 0),
+            macros: HashMap::new(),
+            recording: None,
+            expansion_count: 0,
+            conditions: Vec::new(),
+            aliases: Aliases::default(),
+            charmap: Charmap::default(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            max_errors: None,
+            scopes: Vec::new(),
+            no_major_labels: false,
+            structure: None,
+            assertions: Vec::new(),
         }
     }
 
-    fn write(&mut self, bs: &[u8]) {
-        self.output[self.cursor as usize .. self.cursor as usize + bs.len()].clone_from_slice(bs);
-        self.cursor += bs.len() as u16;
+    fn write(&mut self, bs: &[u8]) -> Result<(), Diagnostic> {
+        if self.cursor as usize + bs.len() > 0x10000 {
+            return Err(self.location.error(Code::OutOfRange, "output would run past address 0xFFFF".to_owned()));
+        }
+
+        let section = &mut self.sections[self.section];
+        for (i, b) in bs.iter().enumerate() {
+            section.image[self.cursor as usize + i] = Some(*b);
+        }
+        section.end = section.end.max(self.cursor + bs.len() as u32);
+        self.cursor += bs.len() as u32;
+        Ok(())
     }
 
-    fn write_word(&mut self, word: u16) {
+    fn write_word(&mut self, word: u16) -> Result<(), Diagnostic> {
         let hi_byte = ((word & 0xFF00) >> 8) as u8;
         let lo_byte = ((word & 0x00FF) >> 0) as u8;
-        self.write(&[ hi_byte, lo_byte ]);
+        self.write(&[ hi_byte, lo_byte ])
     }
 
-    fn write_address(&mut self, addr: Address) {
-        match addr {
-            Address::Label(label) => {
-                if label.starts_with(".") {
-                    self.needs_label.push((self.cursor, self.last_major_label.clone() + &label, Nibble::Both));
-                } else {
-                    self.needs_label.push((self.cursor, label, Nibble::Both));
-                }
-                self.write_word(0x0000);
-            },
-            Address::Immediate(i) => {
-                self.write_word(i);
-            },
-        }
+    /// Prefix of symbols defined in the innermost `scope` block, such as `Outer.Inner.`.
+    fn scope_prefix(&self, depth: usize) -> String {
+        self.scopes[..depth].iter().map(|scope| scope.name.clone() + ".").collect()
     }
 
-    fn write_value(&mut self, value: Value) {
-        match value {
-            Value::Immediate(v) => {
-                self.write(&[ v ]);
-            },
-            Value::Addr(addr, nib) => {
-                match addr {
-                    Address::Label(label) => {
-                        if label.starts_with(".") {
-                            self.needs_label.push((self.cursor, self.last_major_label.clone() + &label, nib));
-                        } else {
-                            self.needs_label.push((self.cursor, label, nib));
-                        }
-                        self.write(&[ 0x00 ]);
-                    },
-                    Address::Immediate(i) => {
-                        match nib {
-                            Nibble::Both => unreachable!(),
-                            Nibble::High => {
-                                let hi_byte = ((i & 0xFF00) >> 8) as u8;
-                                self.write(&[ hi_byte ]);
-                            },
-                            Nibble::Low => {
-                                let lo_byte = ((i & 0x00FF) >> 0) as u8;
-                                self.write(&[ lo_byte ]);
-                            },
-                        }
-                    },
-                }
-            },
+    /// Full name of a symbol defined at the current line.
+    fn qualify_definition(&self, label: Label) -> Label {
+        if label.starts_with(".") {
+            self.last_major_label.clone() + &label
+        } else {
+            self.scope_prefix(self.scopes.len()) + &label
         }
     }
 
-    fn write_registers(&mut self, r0: Register, r1: Register) {
-        self.write(&[ r0.0 << 4 | r1.0 ]);
+    /// Full names a symbol referenced at the current line may resolve to, innermost scope first.
+    fn qualify_reference(&self, label: Label) -> Vec<Label> {
+        if label.starts_with(".") {
+            vec![ self.last_major_label.clone() + &label ]
+        } else if is_numeric_reference(&label) {
+            let (number, direction) = label.split_at(label.len() - 1);
+            let count = *self.numeric_labels.get(number).unwrap_or(&0) as i64;
+            let index = if direction == "b" { count - 1 } else { count };
+            vec![ format!("{}:{}", number, index) ]
+        } else {
+            (0..self.scopes.len() + 1).rev()
+                .map(|depth| self.scope_prefix(depth) + &label)
+                .collect()
+        }
     }
 
-    fn write_serializable(&mut self, value: Serializable) {
-        match value {
-            Serializable::Byte(b)   => self.write(&[ b ]),
-            Serializable::String(s) => self.write(s.as_bytes()),
+    /// Value of `$`, which is relative to the start of the section if it has no base.
+    fn here(&self) -> Expr {
+        if let Some(ref phase) = self.phase {
+            return Expr::Number(phase.run_address(self.line_start) as i64);
         }
-    }
 
-    fn process(&mut self, line: Line) -> Result<(), String> {
-        if let Some(label) = line.label {
-            if label.chars().next().unwrap().is_uppercase() {
-                self.last_major_label = label.clone();
-            }
+        let section = &self.sections[self.section];
+        let start = Expr::Number(self.line_start as i64);
 
-            if label.starts_with(".") {
-                self.label_map.insert(self.last_major_label.clone() + &label, self.cursor);
-            } else {
-                self.label_map.insert(label.clone(), self.cursor);
-            }
+        match section.base {
+            Some(_) => start,
+            None => Expr::Binary(BinaryOp::Add, Box::new(Expr::Label(section_start(&section.name))), Box::new(start)),
         }
+    }
 
-        if let Some(instruction) = line.instruction {
-            use grammar::Instruction::*;
-
-            if let Some(opcode) = instruction.opcode() {
-                if let Some(ref whitelist) = self.enabled_instructions {
-                    if !whitelist.contains_key(&opcode) {
-                        for (mnem, op) in OPCODES.iter() {
-                            if *op == opcode {
-                                return Err(format!("Use of instruction '{}' not allowed with current whitelist", mnem));
-                            }
-                        }
-                        panic!("Opcode set changed between parsing and processing.");
-                    }
-                }
+    /// Qualifies labels and substitutes the current values of `set` constants, except in `sizeof`.
+    fn qualify(&self, expr: Expr) -> Expr {
+        expr.map_labels(&mut |label, in_sizeof| {
+            if label == HERE {
+                return self.here();
             }
 
-            /* Write the binary output */
-            match instruction {
-                Db(vs) => {
-                    for v in vs {
-                        self.write_serializable(v);
-                    }
-                },
-                Ds(len) => {
-                    self.cursor += len;
-                },
-                Org(pos) => {
-                    self.cursor = pos;
-                },
-                Include(_) => {
-                    panic!("Processing include in Compiler::process!");
+            let mut candidates = self.qualify_reference(label);
+
+            if let Some(Constant::Set(value)) = self.find_defined(&candidates).and_then(|c| self.constants.get(c)) {
+                if !in_sizeof {
+                    return Expr::Number(*value);
                 }
-                Nullary(opcode) => {
-                    self.write(&[ opcode ]);
-                },
-                UnaryReg(opcode, register) => {
-                    self.write(&[ opcode, register.0 ]);
-                },
-                UnaryAddr(opcode, address) => {
-                    self.write(&[ opcode ]);
-                    self.write_address(address);
-                },
-                BinaryRegIm(opcode, register, value) => {
-                    self.write(&[ opcode, register.0 ]);
-                    self.write_value(value);
-                },
-                BinaryRegReg(opcode, register0, register1) => {
-                    self.write(&[ opcode ]);
-                    self.write_registers(register0, register1);
-                },
             }
-        }
 
-        Ok(())
+            if candidates.len() == 1 {
+                Expr::Label(candidates.remove(0))
+            } else {
+                Expr::Scoped(candidates)
+            }
+        })
     }
 
-    pub fn compile_file(filename: &str, whitelist: Option<Vec<String>>) -> Result<(Vec<u8>, String), String> {
-        let source = read_to_string(filename);
-        Self::compile(filename, &source, whitelist)
+    fn is_defined(&self, symbol: &Label) -> bool {
+        self.label_map.contains_key(symbol) || self.constants.contains_key(symbol)
     }
 
-    #[allow(dead_code)]
-    pub fn compile_source(source: &str, whitelist: Option<Vec<String>>) -> Result<(Vec<u8>, String), String> {
-        Self::compile("-", source, whitelist)
+    fn find_defined<'a>(&self, candidates: &'a [Label]) -> Option<&'a Label> {
+        candidates.iter().find(|c| self.is_defined(c))
     }
 
-    fn compile(filename: &str, source: &str, whitelist: Option<Vec<String>>) -> Result<(Vec<u8>, String), String> {
-        let mut compiler = Compiler::new();
-
-        if let Some(mnemonics) = whitelist {
-            let mut map = HashMap::new();
+    fn evaluate(&self, expr: &Expr) -> Result<i64, Diagnostic> {
+        self.evaluate_nested(expr, 0)
+    }
 
-            for mnemonic in mnemonics {
-                if let Some(opcode) = OPCODES.get(mnemonic.as_str()) {
-                    map.insert(*opcode, mnemonic);
+    fn evaluate_nested(&self, expr: &Expr, depth: usize) -> Result<i64, Diagnostic> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Scoped(candidates) => {
+                match self.find_defined(candidates) {
+                    Some(label) => self.evaluate_nested(&Expr::Label(label.clone()), depth),
+                    None => Err(Diagnostic::error(Code::UndefinedSymbol, format!("undefined symbol '{}'", candidates.last().unwrap()))),
                 }
-                else {
-                    return Err(format!("Unknown whitelist instruction '{}' in file '{}'", mnemonic, filename));
+            },
+            Expr::Label(label) => {
+                if let Some(addr) = self.label_map.get(label) {
+                    if let Some(&index) = self.relocatable_labels.get(label) {
+                        let name = &self.sections[index].name;
+                        if *label == section_start(name) {
+                            return Err(Diagnostic::error(Code::Evaluation, format!("value of '{}' isn't known until section '{}' is placed", HERE, name)));
+                        }
+                        return Err(Diagnostic::error(Code::Evaluation, format!("address of label '{}' isn't known until section '{}' is placed", label, name)));
+                    }
+                    return Ok(*addr as i64);
                 }
-            }
 
-            compiler.enabled_instructions = Some(map);
+                match self.constants.get(label) {
+                    Some(Constant::Equ(_, _)) if depth >= MAX_EVALUATION_DEPTH => {
+                        Err(Diagnostic::error(Code::Evaluation, format!("constant '{}' is defined recursively", label)))
+                    },
+                    Some(Constant::Equ(e, location)) => {
+                        self.evaluate_nested(e, depth + 1).map_err(|err| match err.code {
+                            Code::Evaluation => err,
+                            _ => err.with_note(format!("in the value of '{}', defined at {}", label, location)),
+                        })
+                    },
+                    Some(Constant::Set(value))
+                    | Some(Constant::Struct(value))
+                    | Some(Constant::Field(value))
+                    | Some(Constant::Predefined(value)) => Ok(*value),
+                    None if label.contains(':') => {
+                        let number = label.split(':').next().unwrap();
+                        Err(Diagnostic::error(Code::UndefinedSymbol, format!("no matching definition of numeric label '{}'", number)))
+                    },
+                    None => Err(Diagnostic::error(Code::UndefinedSymbol, format!("undefined symbol '{}'", label))),
+                }
+            },
+            Expr::Unary(op, e) => Ok(op.apply(self.evaluate_nested(e, depth)?)),
+            Expr::Binary(op, l, r) => {
+                op.apply(self.evaluate_nested(l, depth)?, self.evaluate_nested(r, depth)?)
+                    .map_err(|err| Diagnostic::error(Code::Evaluation, err))
+            },
+            Expr::Sizeof(e) => self.size_of(e, depth),
+            Expr::Spanned(columns, e) => {
+                self.evaluate_nested(e, depth).map_err(|err| match depth {
+                    /* Columns of expressions in `equ` values refer to the defining line */
+                    0 => err.with_columns(Some(columns.clone())),
+                    _ => err,
+                })
+            },
         }
+    }
 
-        let init_lines = source.split('\n')
-            .enumerate()
-            .map(|(i, x)| (i + 1, x.to_owned()))
-            .collect::<Vec<(usize, String)>>().into_iter()
-            .rev()
-            .collect();
-
-        compiler.file_stack.init(filename, init_lines);
+    fn size_of(&self, expr: &Expr, depth: usize) -> Result<i64, Diagnostic> {
+        let label = match expr {
+            Expr::Label(label) => label,
+            Expr::Scoped(candidates) => self.find_defined(candidates).unwrap_or_else(|| candidates.last().unwrap()),
+            Expr::Spanned(columns, e) => {
+                return self.size_of(e, depth).map_err(|err| match depth {
+                    0 => err.with_columns(Some(columns.clone())),
+                    _ => err,
+                });
+            },
+            _ => return Err(Diagnostic::error(Code::InvalidUse, "operand of 'sizeof' is not a struct".to_owned())),
+        };
 
-        while let Some((file, (ln, line))) = compiler.file_stack.pop() {
-            match parse_line(&line) {
-                Ok(l) => {
-                    if let Some(Instruction::Include(path)) = l.instruction {
-                        compiler.file_stack.push(&path)?;
-                    }
-                    else {
-                        compiler.process(l)?
-                    }
-                },
-                Err(mut e) => {
-                    let first = e.expected.iter().nth(0).unwrap().clone();
-                    if e.expected.len() == 1 {
-                        return Err(format!("In {}:{}:{}, expected {}", file, ln, e.column, first));
-                    }
-                        else {
-                            let rest: Vec<&str> = e.expected.iter().skip(1).cloned().collect();
-                            return Err(format!("In {}:{}:{}, expected {} or {}", file, ln, e.column, rest.join(", "), first));
-                        }
-                },
-            }
+        match self.constants.get(label) {
+            Some(Constant::Struct(size)) => Ok(*size),
+            _ if !self.is_defined(label) => Err(Diagnostic::error(Code::UndefinedSymbol, format!("undefined symbol '{}'", label))),
+            _ => Err(Diagnostic::error(Code::InvalidUse, format!("'{}' is not a struct", label))),
         }
+    }
 
-        compiler.resolve_labels()?;
+    /// Evaluates an expression that has to be known at the current line, such as an `org` address.
+    fn evaluate_now(&self, expr: Expr) -> Result<i64, Diagnostic> {
+        self.evaluate(&self.qualify(expr)).map_err(|err| self.location.attach(err))
+    }
 
-        /* Strip trailing zeroes */
-        let mut output = compiler.output.to_vec();
-        while output.last() == Some(&0) {
-            output.pop();
+    fn write_expr(&mut self, expr: Expr, size: Size) -> Result<(), Diagnostic> {
+        let fixup = Fixup {
+            section: self.section,
+            position: self.cursor as u16,
+            expr: self.qualify(expr),
+            size,
+            location: self.location.clone(),
+        };
+
+        match size {
+            Size::Byte => self.write(&[ 0x00 ])?,
+            Size::Word | Size::WordLittle => self.write_word(0x0000)?,
         }
 
-        Ok((output, serde_json::to_string(&compiler.label_map).unwrap()))
+        self.needs_label.push(fixup);
+        Ok(())
     }
 
-    fn resolve_labels(&mut self) -> Result<(), String> {
-        for (position, label, nib) in self.needs_label.iter() {
-            let addr = self.label_map.get(label).ok_or(format!("Undefined label '{}'!", label))?;
+    /// Writes `len` copies of the fill byte, zero unless given.
+    fn fill(&mut self, len: i64, fill: Option<Expr>) -> Result<(), Diagnostic> {
+        let byte = match fill {
+            Some(fill) => {
+                let columns = fill.columns();
+                let value = self.evaluate_now(fill)?;
+                Size::Byte.truncate(value).map_err(|err| self.location.attach(err.with_columns(columns)))? as u8
+            },
+            None => 0x00,
+        };
 
-            match nib {
-                Nibble::Both => {
-                    self.output[*position as usize + 0] = ((addr & 0xff00) >> 8) as u8;
-                    self.output[*position as usize + 1] = ((addr & 0x00ff) >> 0) as u8;
-                },
-                Nibble::High => {
-                    self.output[*position as usize] = ((addr & 0xff00) >> 8) as u8;
-                },
-                Nibble::Low => {
-                    self.output[*position as usize] = ((addr & 0x00ff) >> 0) as u8;
-                },
-            }
+        if self.cursor as i64 + len > 0x10000 {
+            return Err(self.location.error(Code::OutOfRange, format!("{} bytes of fill would run past address 0xFFFF", len)));
         }
 
-        Ok(())
+        self.write(&vec![ byte; len as usize ])
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn write_registers(&mut self, r0: Register, r1: Register) -> Result<(), Diagnostic> {
+        self.write(&[ r0.0 << 4 | r1.0 ])
+    }
 
-    #[test]
-    fn it_produces_output() {
-        let binary = Compiler::compile_source("add R0, R1", None).expect("Failed to compile code");
+    /// Encodes a string literal using the current character map.
+    fn encode(&self, s: &str) -> Result<Vec<u8>, Diagnostic> {
+        self.charmap.encode(s).map_err(|c| {
+            self.location.error(Code::Encoding, format!("character '{}' has no charmap entry", c.escape_default()))
+        })
+    }
 
-        assert_eq!(binary.0, vec![ 0x10, 0x01 ]);
+    fn write_serializable(&mut self, value: Serializable) -> Result<(), Diagnostic> {
+        match value {
+            Serializable::Byte(e)   => self.write_expr(e, Size::Byte),
+            Serializable::String(s) => {
+                let bytes = self.encode(&s)?;
+                self.write(&bytes)
+            },
+        }
     }
 
-    #[test]
-    fn it_resolves_labels() {
+    /// Defines fields of the structure being laid out instead of emitting code.
+    fn process_field(&mut self, line: Line) -> Result<(), Diagnostic> {
+        use grammar::Instruction::*;
+
+        let mut structure = self.structure.take().unwrap();
+
+        if let Some(label) = line.label {
+            let name = format!("{}.{}", structure.name, label);
+            if self.is_defined(&name) {
+                return Err(self.location.error(Code::Redefinition, format!("field '{}' is already defined", name)));
+            }
+            self.constants.insert(name, Constant::Field(structure.size));
+        }
+
+        match line.instruction {
+            Some(Byte) => structure.size += 1,
+            Some(Word) => structure.size += 2,
+            Some(Ds(_, Some(_))) => {
+                return Err(self.location.error(Code::InvalidUse, "fields can't have a fill value".to_owned()));
+            },
+            Some(Ds(len, None)) => {
+                let len = self.evaluate_now(len)?;
+                if len < 0 {
+                    return Err(self.location.error(Code::OutOfRange, format!("invalid length {} in 'ds'", len)));
+                }
+                structure.size += len;
+            },
+            Some(Ends) => {
+                self.constants.insert(structure.name, Constant::Struct(structure.size));
+                return Ok(());
+            },
+            Some(_) => {
+                return Err(self.location.error(Code::InvalidUse, "only 'byte', 'word' and 'ds' are allowed inside a struct".to_owned()));
+            },
+            None => {},
+        }
+
+        self.structure = Some(structure);
+        Ok(())
+    }
+
+    /// Binds `label` to the current location counter.
+    fn define_label(&mut self, label: Label) {
+        if let Some(ref phase) = self.phase {
+            self.load_addresses.insert(label.clone(), (self.cursor as u16, self.section));
+            self.relocatable_labels.remove(&label);
+            self.label_map.insert(label, phase.run_address(self.cursor as u16));
+            return;
+        }
+
+        self.load_addresses.remove(&label);
+        if self.sections[self.section].base.is_none() {
+            self.relocatable_labels.insert(label.clone(), self.section);
+        }
+        else {
+            self.relocatable_labels.remove(&label);
+        }
+        self.label_map.insert(label, self.cursor as u16);
+    }
+
+    /// Switches to the section `name`, creating it if needed.
+    fn switch_section(&mut self, name: String, base: Option<u16>) -> Result<(), Diagnostic> {
+        self.sections[self.section].cursor = self.cursor;
+
+        let index = match self.sections.iter().position(|s| s.name == name) {
+            Some(index) => {
+                let section = &mut self.sections[index];
+                if base.is_some() && base != section.base {
+                    if !section.is_empty() {
+                        return Err(self.location.error(Code::Redefinition, format!("section '{}' already has contents and cannot be moved", name)));
+                    }
+                    *section = Section::new(&name, base);
+                }
+                index
+            },
+            None => {
+                self.sections.push(Section::new(&name, base));
+                self.sections.len() - 1
+            },
+        };
+
+        if self.sections[index].base.is_none() {
+            self.label_map.insert(section_start(&name), 0);
+            self.relocatable_labels.insert(section_start(&name), index);
+        }
+        else {
+            self.label_map.remove(&section_start(&name));
+            self.relocatable_labels.remove(&section_start(&name));
+        }
+
+        self.section = index;
+        self.cursor = self.sections[index].cursor;
+        Ok(())
+    }
+
+    /// Fails unless the whitelist, if there is one, allows `opcode`.
+    fn check_whitelisted(&self, opcode: Opcode) -> Result<(), Diagnostic> {
+        if let Some(ref whitelist) = self.enabled_instructions {
+            if !whitelist.contains_key(&opcode) {
+                for (mnem, op) in OPCODES.iter() {
+                    if *op == opcode {
+                        return Err(self.location.error(Code::Whitelist, format!("use of instruction '{}' not allowed with current whitelist", mnem)));
+                    }
+                }
+                panic!("Opcode set changed between parsing and processing.");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a jump to the handler of each assigned vector, or to the default handler if there is one.
+    fn write_vectors(&mut self) -> Result<(), Diagnostic> {
+        let (section, cursor) = (self.section, self.cursor);
+        self.sections[section].cursor = cursor;
+
+        /* The default section always has a base, so its positions are addresses */
+        self.section = 0;
+        let result = self.write_vector_jumps();
+
+        self.section = section;
+        self.cursor = cursor;
+        result
+    }
+
+    fn write_vector_jumps(&mut self) -> Result<(), Diagnostic> {
+        for (name, address) in self.vector_table.clone() {
+            let (handler, location) = match self.vectors.get(&name).or_else(|| self.vectors.get(DEFAULT_VECTOR)) {
+                Some((handler, location)) => (handler.clone(), location.clone()),
+                None => continue,
+            };
+
+            if (address as usize .. address as usize + 3).any(|a| a > 0xFFFF || self.sections[0].image[a].is_some()) {
+                return Err(location.error(Code::Overlap, format!("vector '{}' at address 0x{:04X} overlaps other output", name, address)));
+            }
+
+            self.cursor = address as u32;
+            self.write(&[ OPCODES["jmp"] ])?;
+            self.needs_label.push(Fixup {
+                section: 0,
+                position: self.cursor as u16,
+                expr: handler,
+                size: Size::Word,
+                location,
+            });
+            self.write_word(0x0000)?;
+        }
+
+        Ok(())
+    }
+
+    /// Places every section in the output, putting sections without a base right after the preceding one.
+    fn place_sections(&mut self) -> Result<(), Diagnostic> {
+        self.sections[self.section].cursor = self.cursor;
+
+        let names = self.sections.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        let mut owners: Vec<Option<usize>> = vec![None; 0x10000];
+        let mut next = 0u32;
+
+        for index in 0..self.sections.len() {
+            let section = &mut self.sections[index];
+            if section.base.is_none() {
+                let start = next.div_ceil(section.alignment) * section.alignment;
+                if start + section.end > 0x10000 {
+                    return Err(Diagnostic::error(Code::OutOfRange, format!("Section '{}' doesn't fit below address 0xFFFF", section.name)));
+                }
+                section.offset = start as u16;
+            }
+            next = section.offset as u32 + section.end;
+
+            for (position, byte) in section.image.iter().enumerate() {
+                if let Some(byte) = byte {
+                    let address = (section.offset as usize + position) & 0xFFFF;
+                    if let Some(owner) = owners[address] {
+                        return Err(Diagnostic::error(Code::Overlap, format!("Sections '{}' and '{}' overlap at address 0x{:04X}", names[owner], section.name, address)));
+                    }
+                    owners[address] = Some(index);
+                    self.output[address] = *byte;
+                }
+            }
+        }
+
+        for (label, index) in self.relocatable_labels.drain() {
+            let addr = self.label_map.get_mut(&label).unwrap();
+            *addr = addr.wrapping_add(self.sections[index].offset);
+        }
+
+        Ok(())
+    }
+
+    fn process(&mut self, line: Line) -> Result<(), Diagnostic> {
+        if self.structure.is_some() {
+            return self.process_field(line);
+        }
+
+        if let Some(label) = line.label {
+            if label.chars().all(|c| c.is_ascii_digit()) {
+                let count = self.numeric_labels.entry(label.clone()).or_insert(0);
+                let label = format!("{}:{}", label, count);
+                *count += 1;
+                self.define_label(label);
+            }
+            else {
+                let is_major = !self.no_major_labels && label.chars().next().unwrap().is_uppercase();
+                let label = self.qualify_definition(label);
+
+                if is_major {
+                    self.last_major_label = label.clone();
+                    self.aliases.leave_scope();
+                }
+
+                if self.constants.contains_key(&label) {
+                    return Err(self.location.error(Code::Redefinition, format!("label '{}' is already defined as a constant", label)));
+                }
+                self.define_label(label);
+            }
+        }
+
+        if let Some(instruction) = line.instruction {
+            use grammar::Instruction::*;
+
+            if let Some(opcode) = instruction.opcode() {
+                self.check_whitelisted(opcode)?;
+            }
+
+            /* Write the binary output */
+            match instruction {
+                Db(vs) => {
+                    for v in vs {
+                        self.write_serializable(v)?;
+                    }
+                },
+                Dw(vs, endianness) => {
+                    let size = match endianness {
+                        Endianness::Big => Size::Word,
+                        Endianness::Little => Size::WordLittle,
+                    };
+                    for v in vs {
+                        self.write_expr(v, size)?;
+                    }
+                },
+                Asciz(s) => {
+                    self.write_serializable(Serializable::String(s))?;
+                    self.write(&[ 0x00 ])?;
+                },
+                Pstr(s) => {
+                    let bytes = self.encode(&s)?;
+                    if bytes.len() > 0xFF {
+                        return Err(self.location.error(Code::OutOfRange, format!("string of {} bytes is too long for 'pstr'", bytes.len())));
+                    }
+                    self.write(&[ bytes.len() as u8 ])?;
+                    self.write(&bytes)?;
+                },
+                Def(name, register) => {
+                    if Register::parse(&name).is_some() {
+                        return Err(self.location.error(Code::InvalidUse, format!("register '{}' can't be used as an alias", name)));
+                    }
+                    let global = self.last_major_label.is_empty();
+                    if self.aliases.define(&name, register, global) {
+                        self.warnings.push(self.location.warning(Code::AliasInUse, format!("alias '{}' is redefined while still in use", name)));
+                    }
+                },
+                Scope(name) => {
+                    let scope = self::Scope {
+                        name,
+                        location: self.location.clone(),
+                        last_major_label: self.last_major_label.clone(),
+                    };
+                    self.scopes.push(scope);
+
+                    let prefix = self.scope_prefix(self.scopes.len());
+                    self.last_major_label = prefix[..prefix.len() - 1].to_owned();
+                    self.aliases.leave_scope();
+                },
+                Endscope => {
+                    match self.scopes.pop() {
+                        Some(scope) => self.last_major_label = scope.last_major_label,
+                        None => return Err(self.location.error(Code::UnbalancedBlock, "'endscope' without a matching 'scope'".to_owned())),
+                    }
+                    self.aliases.leave_scope();
+                },
+                Struct(name) => {
+                    let name = self.qualify_definition(name);
+                    if self.is_defined(&name) {
+                        return Err(self.location.error(Code::Redefinition, format!("symbol '{}' is already defined", name)));
+                    }
+                    self.structure = Some(Structure {
+                        name,
+                        location: self.location.clone(),
+                        size: 0,
+                    });
+                },
+                Ends => {
+                    return Err(self.location.error(Code::UnbalancedBlock, "'ends' without a matching 'struct'".to_owned()));
+                },
+                Byte | Word => {
+                    return Err(self.location.error(Code::InvalidUse, "fields can only be declared inside a struct".to_owned()));
+                },
+                Charmap(chars, Some(value)) => {
+                    let first = self.evaluate_now(value)?;
+                    for (i, c) in chars.chars().enumerate() {
+                        let byte = Size::Byte.truncate(first + i as i64)
+                            .map_err(|err| self.location.attach(err))?;
+                        self.charmap.insert(c, byte as u8);
+                    }
+                },
+                Charmap(file, None) => {
+                    let path = self.file_stack.find(&file).map_err(|err| self.location.attach(err))?;
+                    let contents = fs::read_to_string(&path)
+                        .map_err(|err| self.location.error(Code::File, format!("failed to read '{}': {}", path, err)))?;
+
+                    let map: HashMap<String, u8> = serde_json::from_str(&contents)
+                        .map_err(|err| self.location.error(Code::File, format!("failed to parse charmap '{}': {}", path, err)))?;
+
+                    for (key, byte) in map {
+                        let mut chars = key.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => self.charmap.insert(c, byte),
+                            _ => return Err(self.location.error(Code::File, format!("charmap '{}' maps '{}', which is not a single character", path, key))),
+                        }
+                    }
+                },
+                Incbin(file, offset, length) => {
+                    let path = self.file_stack.find(&file).map_err(|err| self.location.attach(err))?;
+                    let data = fs::read(&path)
+                        .map_err(|err| self.location.error(Code::File, format!("failed to read '{}': {}", path, err)))?;
+
+                    let offset = match offset {
+                        Some(offset) => self.evaluate_now(offset)?,
+                        None => 0,
+                    };
+                    if offset < 0 || offset > data.len() as i64 {
+                        return Err(self.location.error(Code::OutOfRange, format!("offset {} is outside of file '{}'", offset, path)));
+                    }
+
+                    let length = match length {
+                        Some(length) => self.evaluate_now(length)?,
+                        None => data.len() as i64 - offset,
+                    };
+                    if length < 0 || offset + length > data.len() as i64 {
+                        return Err(self.location.error(Code::OutOfRange, format!("length {} is outside of file '{}'", length, path)));
+                    }
+
+                    if self.cursor as i64 + length > 0x10000 {
+                        return Err(self.location.error(Code::OutOfRange, format!("{} bytes from '{}' would run past address 0xFFFF", length, path)));
+                    }
+
+                    self.write(&data[offset as usize .. (offset + length) as usize])?;
+                },
+                Ds(len, fill) => {
+                    let len = self.evaluate_now(len)?;
+                    if !(0..=0xFFFF).contains(&len) {
+                        return Err(self.location.error(Code::OutOfRange, format!("invalid length {} in 'ds'", len)));
+                    }
+                    self.fill(len, fill)?;
+                },
+                Res(len) => {
+                    let len = self.evaluate_now(len)?;
+                    if !(0..=0xFFFF).contains(&len) {
+                        return Err(self.location.error(Code::OutOfRange, format!("invalid length {} in 'res'", len)));
+                    }
+                    if self.cursor as i64 + len > 0x10000 {
+                        return Err(self.location.error(Code::OutOfRange, format!("{} reserved bytes would run past address 0xFFFF", len)));
+                    }
+
+                    /* Move the location counter without writing to the image */
+                    self.cursor += len as u32;
+                    let section = &mut self.sections[self.section];
+                    section.end = section.end.max(self.cursor);
+                },
+                Align(alignment, fill) => {
+                    let alignment = self.evaluate_now(alignment)?;
+                    if alignment <= 0 || alignment > 0x10000 {
+                        return Err(self.location.error(Code::OutOfRange, format!("invalid alignment {}", alignment)));
+                    }
+                    let section = &mut self.sections[self.section];
+                    section.alignment = section.alignment.max(alignment as u32);
+                    let gap = (alignment - self.cursor as i64 % alignment) % alignment;
+                    self.fill(gap, fill)?;
+                },
+                Org(_) if self.phase.is_some() => {
+                    return Err(self.location.error(Code::InvalidUse, "'org' inside a 'phase' block".to_owned()));
+                },
+                Org(pos) => {
+                    if self.sections[self.section].base.is_none() {
+                        return Err(self.location.error(Code::InvalidUse, format!("'org' in section '{}', which has no base address", self.sections[self.section].name)));
+                    }
+                    let pos = self.evaluate_now(pos)?;
+                    if !(0..=0xFFFF).contains(&pos) {
+                        return Err(self.location.error(Code::OutOfRange, format!("address {} is out of range", pos)));
+                    }
+                    self.cursor = pos as u32;
+                },
+                Assert(expr, message) => {
+                    let assertion = Assertion {
+                        expr: self.qualify(expr),
+                        message,
+                        location: self.location.clone(),
+                    };
+                    self.assertions.push(assertion);
+                },
+                Error(message) => {
+                    return Err(self.location.error(Code::User, message));
+                },
+                Warning(message) => {
+                    self.warnings.push(self.location.warning(Code::UserWarning, message));
+                },
+                Phase(address) => {
+                    if let Some(ref phase) = self.phase {
+                        return Err(self.location.error(Code::InvalidUse, format!("'phase' inside the 'phase' block started in {}", phase.location)));
+                    }
+                    let address = self.evaluate_now(address)?;
+                    if !(0..=0xFFFF).contains(&address) {
+                        return Err(self.location.error(Code::OutOfRange, format!("address {} is out of range", address)));
+                    }
+                    self.phase = Some(self::Phase {
+                        address: address as u16,
+                        start: self.cursor as u16,
+                        location: self.location.clone(),
+                    });
+                },
+                Dephase => {
+                    if self.phase.take().is_none() {
+                        return Err(self.location.error(Code::UnbalancedBlock, "'dephase' without a matching 'phase'".to_owned()));
+                    }
+                },
+                Vector(name, handler) => {
+                    if name != DEFAULT_VECTOR && !self.vector_table.iter().any(|(n, _)| *n == name) {
+                        let names = self.vector_table.iter()
+                            .map(|(n, _)| format!("'{}'", n))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(self.location.error(Code::InvalidUse, format!("unknown vector '{}', expected {} or '{}'", name, names, DEFAULT_VECTOR)));
+                    }
+                    if let Some((_, location)) = self.vectors.get(&name) {
+                        return Err(self.location.error(Code::Redefinition, format!("vector '{}' is already assigned in {}", name, location)));
+                    }
+                    /* Each vector is a jump to its handler */
+                    self.check_whitelisted(OPCODES["jmp"])?;
+                    let handler = self.qualify(handler);
+                    self.vectors.insert(name, (handler, self.location.clone()));
+                },
+                Section(_, _) if self.phase.is_some() => {
+                    return Err(self.location.error(Code::InvalidUse, "can't switch sections inside a 'phase' block".to_owned()));
+                },
+                Section(name, base) => {
+                    let base = match base {
+                        Some(base) => {
+                            let base = self.evaluate_now(base)?;
+                            if !(0..=0xFFFF).contains(&base) {
+                                return Err(self.location.error(Code::OutOfRange, format!("address {} is out of range", base)));
+                            }
+                            Some(base as u16)
+                        },
+                        None => None,
+                    };
+                    self.switch_section(name, base)?;
+                },
+                PragmaOnce => {
+                    self.file_stack.mark_once();
+                },
+                Include(_, _) => {
+                    panic!("Processing include in Compiler::process!");
+                }
+                If(_) | Ifdef(_) | Ifndef(_) | Elif(_) | Else | Endif => {
+                    panic!("Processing conditional in Compiler::process!");
+                }
+                Macro(_, _) | Endm | MacroCall(_, _) | Rept(_, _) | Endr => {
+                    panic!("Processing block in Compiler::process!");
+                }
+                Equ(name, expr) => {
+                    let name = self.qualify_definition(name);
+                    if self.is_defined(&name) {
+                        return Err(self.location.error(Code::Redefinition, format!("symbol '{}' is already defined", name)));
+                    }
+                    let expr = self.qualify(expr);
+                    self.constants.insert(name, Constant::Equ(expr, self.location.clone()));
+                },
+                Set(name, expr) => {
+                    let name = self.qualify_definition(name);
+                    match self.constants.get(&name) {
+                        Some(Constant::Set(_)) => {},
+                        _ if self.is_defined(&name) => {
+                            return Err(self.location.error(Code::Redefinition, format!("symbol '{}' is already defined and cannot be reassigned", name)));
+                        },
+                        _ => {},
+                    }
+                    let value = self.evaluate_now(expr)?;
+                    self.constants.insert(name, Constant::Set(value));
+                },
+                Nullary(opcode) => {
+                    self.write(&[ opcode ])?;
+                },
+                UnaryReg(opcode, register) => {
+                    self.write(&[ opcode, register.0 ])?;
+                },
+                UnaryAddr(opcode, address) => {
+                    self.write(&[ opcode ])?;
+                    self.write_expr(address, Size::Word)?;
+                },
+                BinaryRegIm(opcode, register, value) => {
+                    self.write(&[ opcode, register.0 ])?;
+                    self.write_expr(value, Size::Byte)?;
+                },
+                BinaryRegReg(opcode, register0, register1) => {
+                    self.write(&[ opcode ])?;
+                    self.write_registers(register0, register1)?;
+                },
+                Pseudo(instructions) => {
+                    for instruction in instructions {
+                        self.process(Line { label: None, instruction: Some(instruction) })?;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn compile_file(filename: &str, options: Options) -> Result<(Vec<u8>, String, Vec<Diagnostic>), Errors> {
+        let source = read_to_string(filename);
+        Self::compile(filename, &source, options)
+    }
+
+    #[allow(dead_code)]
+    pub fn compile_source(source: &str, whitelist: Option<Vec<String>>) -> Result<(Vec<u8>, String, Vec<Diagnostic>), Errors> {
+        let options = Options {
+            whitelist,
+            ..Options::default()
+        };
+        Self::compile("-", source, options)
+    }
+
+    fn compile(filename: &str, source: &str, options: Options) -> Result<(Vec<u8>, String, Vec<Diagnostic>), Errors> {
+        let mut compiler = Compiler::new();
+        compiler.no_major_labels = options.no_major_labels;
+        compiler.max_errors = options.max_errors;
+        if let Some(vectors) = options.vectors {
+            compiler.vector_table = vectors;
+        }
+        compiler.file_stack.search_paths = options.include_paths.iter().map(PathBuf::from).collect();
+
+        if let Some(mnemonics) = options.whitelist {
+            let mut map = HashMap::new();
+
+            for mnemonic in mnemonics {
+                if let Some(opcode) = OPCODES.get(mnemonic.as_str()) {
+                    map.insert(*opcode, mnemonic);
+                }
+                else {
+                    return Err(Diagnostic::error(Code::InvalidOption, format!("Unknown whitelist instruction '{}' in file '{}'", mnemonic, filename)).into());
+                }
+            }
+
+            compiler.enabled_instructions = Some(map);
+        }
+
+        for &(name, value) in BUILTIN_SYMBOLS {
+            compiler.constants.insert(name.to_owned(), Constant::Predefined(value));
+        }
+        for define in options.defines.iter() {
+            let (name, value) = parse_define(define)?;
+            compiler.constants.insert(name, Constant::Predefined(value));
+        }
+        for name in options.undefines.iter() {
+            compiler.constants.remove(name);
+        }
+
+        let result = compiler.assemble(filename, source);
+        let warnings = mem::take(&mut compiler.warnings);
+
+        if let Err(mut errors) = result {
+            errors.warnings = warnings;
+            return Err(errors);
+        }
+
+        /* Strip trailing zeroes */
+        let mut output = compiler.output.to_vec();
+        while output.last() == Some(&0) {
+            output.pop();
+        }
+
+        match compiler.symfile() {
+            Ok(symbols) => Ok((output, symbols, warnings)),
+            Err(err) => {
+                let mut errors = Errors::from(err);
+                errors.warnings = warnings;
+                Err(errors)
+            },
+        }
+    }
+
+    fn assemble(&mut self, filename: &str, source: &str) -> Result<(), Errors> {
+        let truncated = self.assemble_all(filename, source).is_err();
+
+        if self.errors.is_empty() {
+            Ok(())
+        }
+        else {
+            /* Errors found once all lines are read, such as undefined labels, are reported in source order too */
+            let mut diagnostics = mem::take(&mut self.errors);
+            diagnostics.sort_by_key(|d| d.index.unwrap_or(usize::MAX));
+
+            Err(Errors {
+                diagnostics,
+                truncated,
+                warnings: Vec::new(),
+            })
+        }
+    }
+
+    /// Records the error of `result`, if any, failing once the error limit is reached.
+    fn report<T>(&mut self, result: Result<T, Diagnostic>) -> Result<Option<T>, ErrorLimit> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => {
+                self.errors.push(err);
+                match self.max_errors {
+                    Some(max) if self.errors.len() >= max => Err(ErrorLimit),
+                    _ => Ok(None),
+                }
+            },
+        }
+    }
+
+    fn assemble_all(&mut self, filename: &str, source: &str) -> Result<(), ErrorLimit> {
+        let init_lines = source.split('\n')
+            .enumerate()
+            .map(|(i, x)| (i + 1, x.to_owned()))
+            .collect::<Vec<(usize, String)>>().into_iter()
+            .rev()
+            .collect();
+
+        self.file_stack.init(filename, init_lines);
+
+        while let Some((location, line)) = self.file_stack.pop() {
+            self.location = location;
+            let result = self.compile_line(&line);
+            self.report(result)?;
+        }
+
+        for err in self.unterminated_blocks() {
+            self.report::<()>(Err(err))?;
+        }
+
+        let result = self.write_vectors();
+        self.report(result)?;
+
+        let result = self.place_sections();
+        if self.report(result)?.is_none() {
+            return Ok(());
+        }
+
+        self.resolve_labels()?;
+        self.check_assertions()
+    }
+
+    /// Errors for blocks still open at the end of the source.
+    fn unterminated_blocks(&mut self) -> Vec<Diagnostic> {
+        let mut errors = Vec::new();
+
+        if let Some(recording) = self.recording.take() {
+            errors.push(match recording.block {
+                Block::Macro(name, _) => recording.location.error(Code::UnbalancedBlock, format!("macro '{}' is missing 'endm'", name)),
+                Block::Rept(_, _) => recording.location.error(Code::UnbalancedBlock, "'rept' block is missing 'endr'".to_owned()),
+            });
+        }
+
+        if let Some(condition) = self.conditions.last() {
+            errors.push(condition.location.error(Code::UnbalancedBlock, "conditional block is missing 'endif'".to_owned()));
+        }
+
+        if let Some(ref structure) = self.structure {
+            errors.push(structure.location.error(Code::UnbalancedBlock, format!("struct '{}' is missing 'ends'", structure.name)));
+        }
+
+        if let Some(scope) = self.scopes.last() {
+            errors.push(scope.location.error(Code::UnbalancedBlock, format!("scope '{}' is missing 'endscope'", scope.name)));
+        }
+
+        if let Some(ref phase) = self.phase {
+            errors.push(phase.location.error(Code::UnbalancedBlock, "'phase' block is missing 'dephase'".to_owned()));
+        }
+
+        errors
+    }
+
+    /// Serializes all symbols into a JSON object, marking each with its kind.
+    fn symfile(&self) -> Result<String, Diagnostic> {
+        let mut symbols = serde_json::Map::new();
+
+        for (label, addr) in self.label_map.iter().filter(|&(label, _)| !label.contains(':')) {
+            let symbol = match self.load_addresses.get(label) {
+                Some(&(load, section)) => {
+                    let load = load.wrapping_add(self.sections[section].offset);
+                    json!({ "kind": "label", "value": addr, "load": load })
+                },
+                None => json!({ "kind": "label", "value": addr }),
+            };
+            symbols.insert(label.clone(), symbol);
+        }
+
+        for (name, constant) in self.constants.iter() {
+            let symbol = match constant {
+                Constant::Equ(expr, location) => {
+                    let value = self.evaluate(expr).map_err(|err| location.attach(err))?;
+                    json!({ "kind": "equ", "value": value })
+                },
+                Constant::Set(value) => json!({ "kind": "set", "value": value }),
+                Constant::Struct(value) => json!({ "kind": "struct", "value": value }),
+                Constant::Field(value) => json!({ "kind": "field", "value": value }),
+                Constant::Predefined(value) => json!({ "kind": "predefined", "value": value }),
+            };
+            symbols.insert(name.clone(), symbol);
+        }
+
+        Ok(serde_json::to_string(&symbols).unwrap())
+    }
+
+    fn compile_line(&mut self, line: &str) -> Result<(), Diagnostic> {
+        self.line_start = self.cursor as u16;
+
+        if self.recording.is_some() {
+            return self.record_line(line);
+        }
+
+        if let Ok(directive) = parse_block_directive(line, &self.aliases, &self.charmap) {
+            match directive {
+                Instruction::If(_) | Instruction::Ifdef(_) | Instruction::Ifndef(_)
+                | Instruction::Elif(_) | Instruction::Else | Instruction::Endif => {
+                    return self.conditional(directive);
+                },
+                _ => {},
+            }
+        }
+
+        if !self.is_assembling() {
+            return Ok(());
+        }
+
+        let mut l = parse_line(line, &self.aliases, &self.charmap).map_err(|e| self.parse_error(e))?;
+
+        match l.instruction.take() {
+            Some(Instruction::Include(path, once)) => {
+                self.process(l)?;
+                self.file_stack.push(&path, once, &self.location)
+            },
+            Some(Instruction::Macro(name, params)) => {
+                if l.label.is_some() {
+                    return Err(self.location.error(Code::InvalidUse, "macro definition can't be labeled".to_owned()));
+                }
+                if self.macros.contains_key(&name) {
+                    return Err(self.location.error(Code::Redefinition, format!("macro '{}' is already defined", name)));
+                }
+
+                self.record(Block::Macro(name, params));
+                Ok(())
+            },
+            Some(Instruction::Endm) => {
+                Err(self.location.error(Code::UnbalancedBlock, "'endm' without a matching 'macro'".to_owned()))
+            },
+            Some(Instruction::Rept(count, var)) => {
+                self.process(l)?;
+
+                let result = self.evaluate_now(count).and_then(|count| {
+                    if !(0..=MAX_REPEAT_COUNT).contains(&count) {
+                        return Err(self.location.error(Code::OutOfRange, format!("invalid repeat count {}", count)));
+                    }
+                    Ok(count)
+                });
+
+                /* Record the body even if the count is invalid, so that it isn't assembled on its own */
+                self.record(Block::Rept(*result.as_ref().unwrap_or(&0), var));
+                result.map(|_| ())
+            },
+            Some(Instruction::Endr) => {
+                Err(self.location.error(Code::UnbalancedBlock, "'endr' without a matching 'rept'".to_owned()))
+            },
+            Some(Instruction::MacroCall(name, args)) => {
+                self.process(l)?;
+                self.expand_macro(name, args)
+            },
+            instruction => {
+                l.instruction = instruction;
+                self.process(l)
+            },
+        }
+    }
+
+    fn parse_error(&self, e: ParseError) -> Diagnostic {
+        let first = e.expected.iter().nth(0).unwrap().clone();
+        let message = if e.expected.len() == 1 {
+            format!("expected {}", first)
+        }
+        else {
+            let rest: Vec<&str> = e.expected.iter().skip(1).cloned().collect();
+            format!("expected {} or {}", rest.join(", "), first)
+        };
+
+        let mut diagnostic = self.location.error(Code::Syntax, message);
+        diagnostic.columns = Some(e.column .. e.column + 1);
+        diagnostic
+    }
+
+    fn is_assembling(&self) -> bool {
+        self.conditions.last().is_none_or(|c| c.active)
+    }
+
+    fn conditional(&mut self, directive: Instruction) -> Result<(), Diagnostic> {
+        use grammar::Instruction::*;
+
+        let enclosing = self.is_assembling();
+
+        let mut error = None;
+        let opening = match directive {
+            If(ref expr) if enclosing => match self.evaluate_now(expr.clone()) {
+                Ok(value) => Some(value != 0),
+                Err(err) => {
+                    /* Skip the whole block, so that its branches don't cause further errors */
+                    error = Some(err);
+                    Some(false)
+                },
+            },
+            Ifdef(ref name) if enclosing => Some(self.find_defined(&self.qualify_reference(name.clone())).is_some()),
+            Ifndef(ref name) if enclosing => Some(self.find_defined(&self.qualify_reference(name.clone())).is_none()),
+            If(_) | Ifdef(_) | Ifndef(_) => Some(false),
+            _ => None,
+        };
+
+        if let Some(active) = opening {
+            self.conditions.push(Condition {
+                location: self.location.clone(),
+                active,
+                taken: active || !enclosing || error.is_some(),
+                seen_else: false,
+            });
+            return match error {
+                Some(err) => Err(err),
+                None => Ok(()),
+            };
+        }
+
+        let (taken, seen_else) = match self.conditions.last() {
+            Some(condition) => (condition.taken, condition.seen_else),
+            None => return Err(self.location.error(Code::UnbalancedBlock, "conditional directive without a matching 'if'".to_owned())),
+        };
+
+        match directive {
+            Elif(_) | Else if seen_else => {
+                Err(self.location.error(Code::UnbalancedBlock, "conditional directive after 'else'".to_owned()))
+            },
+            Elif(expr) => {
+                let value = if taken { Ok(0) } else { self.evaluate_now(expr) };
+                let active = value.as_ref().is_ok_and(|&value| value != 0);
+                let condition = self.conditions.last_mut().unwrap();
+                condition.active = active;
+                condition.taken = taken || active || value.is_err();
+                value.map(|_| ())
+            },
+            Else => {
+                let condition = self.conditions.last_mut().unwrap();
+                condition.active = !taken;
+                condition.taken = true;
+                condition.seen_else = true;
+                Ok(())
+            },
+            Endif => {
+                self.conditions.pop();
+                Ok(())
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn record(&mut self, block: Block) {
+        self.recording = Some(Recording {
+            block,
+            body: Vec::new(),
+            location: self.location.clone(),
+            depth: 0,
+        });
+    }
+
+    /// Collects the body of the block being recorded, up to the matching terminator.
+    fn record_line(&mut self, line: &str) -> Result<(), Diagnostic> {
+        let mut recording = self.recording.take().unwrap();
+
+        let (opens, closes) = match (&recording.block, parse_block_directive(line, &self.aliases, &self.charmap)) {
+            (&Block::Macro(_, _), Ok(Instruction::Macro(_, _))) => (true, false),
+            (&Block::Macro(_, _), Ok(Instruction::Endm)) => (false, true),
+            (&Block::Rept(_, _), Ok(Instruction::Rept(_, _))) => (true, false),
+            (&Block::Rept(_, _), Ok(Instruction::Endr)) => (false, true),
+            _ => (false, false),
+        };
+
+        if closes && recording.depth == 0 {
+            return self.finish_recording(recording);
+        }
+
+        if opens {
+            recording.depth += 1;
+        }
+        if closes {
+            recording.depth -= 1;
+        }
+
+        recording.body.push((self.location.line, line.to_owned()));
+        self.recording = Some(recording);
+
+        Ok(())
+    }
+
+    fn finish_recording(&mut self, recording: Recording) -> Result<(), Diagnostic> {
+        match recording.block {
+            Block::Macro(name, params) => {
+                let definition = Macro {
+                    params,
+                    body: recording.body,
+                    location: recording.location,
+                };
+                self.macros.insert(name, definition);
+                Ok(())
+            },
+            Block::Rept(count, var) => {
+                let mut lines = Vec::new();
+
+                for i in 0..count {
+                    self.expansion_count += 1;
+
+                    let mut arguments = HashMap::new();
+                    arguments.insert("@".to_owned(), format!("_{}", self.expansion_count));
+
+                    if let Some(ref var) = var {
+                        lines.push((recording.location.line, format!("{} set {}", var, i)));
+                        arguments.insert(var.clone(), i.to_string());
+                    }
+
+                    lines.extend(recording.body.iter().map(|&(ln, ref line)| (ln, substitute(line, &arguments))));
+                }
+
+                let expansion = Expansion {
+                    name: "rept".to_owned(),
+                    location: recording.location.clone(),
+                };
+
+                self.file_stack.expand(&recording.location.file, lines, expansion)
+            },
+        }
+    }
+
+    fn expand_macro(&mut self, name: String, args: Vec<String>) -> Result<(), Diagnostic> {
+        let definition = match self.macros.get(&name) {
+            Some(definition) => definition.clone(),
+            None => return Err(self.location.error(Code::UndefinedSymbol, format!("unknown instruction or macro '{}'", name))),
+        };
+
+        if args.len() != definition.params.len() {
+            return Err(self.location.error(Code::InvalidUse, format!("macro '{}' expects {} arguments, got {}", name, definition.params.len(), args.len())));
+        }
+
+        self.expansion_count += 1;
+
+        let mut arguments: HashMap<String, String> = definition.params.into_iter().zip(args).collect();
+        arguments.insert("@".to_owned(), format!("_{}", self.expansion_count));
+
+        let lines = definition.body.iter()
+            .map(|&(ln, ref line)| (ln, substitute(line, &arguments)))
+            .collect();
+
+        let expansion = Expansion {
+            name,
+            location: self.location.clone(),
+        };
+
+        self.file_stack.expand(&definition.location.file, lines, expansion)
+    }
+
+    fn check_assertions(&mut self) -> Result<(), ErrorLimit> {
+        let assertions = mem::take(&mut self.assertions);
+
+        for assertion in assertions {
+            let result = self.evaluate(&assertion.expr).map_err(|err| assertion.location.attach(err));
+            if self.report(result)? == Some(0) {
+                let err = match assertion.message {
+                    Some(ref message) => assertion.location.error(Code::Assertion, format!("assertion failed: {}", message)),
+                    None => assertion.location.error(Code::Assertion, "assertion failed".to_owned()),
+                };
+                self.report::<()>(Err(err))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self) -> Result<(), ErrorLimit> {
+        let fixups = mem::take(&mut self.needs_label);
+
+        for fixup in fixups {
+            let result = self.evaluate(&fixup.expr)
+                .and_then(|v| fixup.size.truncate(v).map_err(|err| err.with_columns(fixup.expr.columns())))
+                .map_err(|err| fixup.location.attach(err));
+            let value = match self.report(result)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let position = fixup.position.wrapping_add(self.sections[fixup.section].offset) as usize;
+
+            match fixup.size {
+                Size::Word => {
+                    self.output[position + 0] = ((value & 0xff00) >> 8) as u8;
+                    self.output[position + 1] = ((value & 0x00ff) >> 0) as u8;
+                },
+                Size::WordLittle => {
+                    self.output[position + 0] = ((value & 0x00ff) >> 0) as u8;
+                    self.output[position + 1] = ((value & 0xff00) >> 8) as u8;
+                },
+                Size::Byte => {
+                    self.output[position] = value as u8;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diagnostic::Severity;
+    use temp_path::TempPath;
+
+    #[test]
+    fn it_produces_output() {
+        let binary = Compiler::compile_source("add R0, R1", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x10, 0x01 ]);
+    }
+
+    #[test]
+    fn it_resolves_labels() {
+        let binary = Compiler::compile_source("
+            nop
+            nop
+            foo:
+                nop
+                jmp foo
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x00, 0x00, 0x00, 0x20, 0x00, 0x02 ]);
+    }
+
+    #[test]
+    fn it_resolves_local_labels() {
+        let binary = Compiler::compile_source("
+            First:
+            .loop:
+                jmp .loop
+
+            Second:
+            .loop:
+                jmp .loop
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x20, 0x00, 0x00, 0x20, 0x00, 0x03 ]);
+    }
+
+    #[test]
+    fn string_literals_are_not_zero_terminated() {
+        let binary = Compiler::compile_source("
+            db 0xAA, \"a\", 0xBB
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0xAA, 0x61, 0xBB ]);
+    }
+
+    #[test]
+    fn it_produces_a_symfile() {
+        let binary = Compiler::compile_source("
+            org 0x0
+            A:
+            org 0x100
+            B:
+            org 0x40
+            C:
+        ", None).expect("Failed to compile code");
+
+        let syms: serde_json::Value = serde_json::from_str(&binary.1).expect("Failed to read symfile as json");
+
+        assert_eq!(syms["A"]["kind"], "label");
+        assert_eq!(syms["A"]["value"], 0x0);
+        assert_eq!(syms["B"]["value"], 0x100);
+        assert_eq!(syms["C"]["value"], 0x40);
+    }
+
+    #[test]
+    fn it_respects_whitelist() {
+        let binary = Compiler::compile_source("
+            add R0, R1
+            sub R0, R1
+        ", Some(vec![ "add".to_owned() ]));
+
+        assert!(binary.is_err());
+    }
+
+    #[test]
+    fn it_resolves_high_low_addr() {
+        let binary = Compiler::compile_source("
+            org 0x0
+            ldi R0, hi(addr)
+            ldi R1, lo(addr)
+            org 0xABBA
+            addr:
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x31, 0x00, 0xAB, 0x31, 0x01, 0xBA ]);
+    }
+
+    #[test]
+    fn it_evaluates_expressions() {
+        let binary = Compiler::compile_source("
+            org 2 * (1 + 1)
+            jmp table + 4
+            ldi R0, lo(table + 0x10 - 1)
+            ldi R1, -1
+            db ~0x0F & 0xFF, 1 << 4 | 3, 7 % 4
+            org 0x1234
+            table:
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0[4..], [ 0x20, 0x12, 0x38, 0x31, 0x00, 0x43, 0x31, 0x01, 0xFF, 0xF0, 0x13, 0x03 ]);
+    }
+
+    #[test]
+    fn it_reports_operand_overflow() {
         let binary = Compiler::compile_source("
             nop
-            nop
-            foo:
+            ldi R0, 100 * 3
+        ", None);
+
+        assert_eq!(binary.unwrap_err().to_string(), "In -:3:21, value 300 is out of range for a byte operand");
+    }
+
+    #[test]
+    fn it_resolves_constants() {
+        let binary = Compiler::compile_source("
+            PORT equ 0x10
+            out R0, PORT + 1
+            jmp END
+            N set 1
+            db N
+            N set N + 1
+            db N
+            END equ 0x1000 + SIZE
+            SIZE equ 4
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x3B, 0x00, 0x11, 0x20, 0x10, 0x04, 0x01, 0x02 ]);
+
+        let syms: serde_json::Value = serde_json::from_str(&binary.1).expect("Failed to read symfile as json");
+
+        assert_eq!(syms["PORT"]["kind"], "equ");
+        assert_eq!(syms["END"]["value"], 0x1004);
+        assert_eq!(syms["N"]["kind"], "set");
+        assert_eq!(syms["N"]["value"], 2);
+    }
+
+    #[test]
+    fn equ_constants_are_immutable() {
+        let binary = Compiler::compile_source("
+            N equ 1
+            N equ 2
+        ", None);
+
+        assert!(binary.is_err());
+    }
+
+    #[test]
+    fn it_expands_macros() {
+        let binary = Compiler::compile_source("
+            macro load16 rh, rl, addr
+                ldi \\rh, hi(\\addr)
+                ldi \\rl, lo(\\addr)
+            endm
+
+            macro wait reg
+            .loop\\@:
+                dec \\reg
+                brnz .loop\\@
+            endm
+
+            macro setup
+                load16 R0, R1, 0x1234
+                wait R2
+            endm
+
+            Main:
+                setup
+                wait R3
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![
+            0x31, 0x00, 0x12, 0x31, 0x01, 0x34,
+            0x15, 0x02, 0x27, 0x00, 0x06,
+            0x15, 0x03, 0x27, 0x00, 0x0B,
+        ]);
+    }
+
+    #[test]
+    fn macro_errors_report_call_site() {
+        let binary = Compiler::compile_source("
+            macro load value
+                ldi R0, \\value
+            endm
+            load 0x100
+        ", None);
+
+        assert_eq!(binary.unwrap_err().to_string(), "In -:3:25 (in 'load' invoked at -:5), value 256 is out of range for a byte operand");
+    }
+
+    #[test]
+    fn it_assembles_conditionally() {
+        let binary = Compiler::compile_source("
+            REVISION equ 2
+            if REVISION == 1
+            elif REVISION > 1
+                ifdef UNDEFINED
+                    this line is not parsed
+                elif REVISION - 2
+                    nop
+                else
+                    db 0xAA
+                endif
+            else
+                db 0xBB
+            endif
+            ifndef DEBUG
+                db 0xCC
+            endif
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0xAA, 0xCC ]);
+    }
+
+    #[test]
+    fn unterminated_conditionals_are_reported() {
+        let binary = Compiler::compile_source("
+            if 1
                 nop
-                jmp foo
+        ", None);
+
+        assert_eq!(binary.unwrap_err().to_string(), "In -:2, conditional block is missing 'endif'");
+    }
+
+    #[test]
+    fn it_repeats_blocks() {
+        let binary = Compiler::compile_source("
+            rept 2, i
+                rept 3, j
+                    db i * 0x10 + j
+                endr
+            endr
+            rept 2, n
+            entry\\n:
+                jmp entry\\n
+            endr
         ", None).expect("Failed to compile code");
 
-        assert_eq!(binary.0, vec![ 0x00, 0x00, 0x00, 0x20, 0x00, 0x02 ]);
+        assert_eq!(binary.0, vec![
+            0x00, 0x01, 0x02, 0x10, 0x11, 0x12,
+            0x20, 0x00, 0x06, 0x20, 0x00, 0x09,
+        ]);
     }
 
     #[test]
-    fn it_resolves_local_labels() {
+    fn it_rejects_huge_repeat_counts() {
+        let errors = Compiler::compile_source("
+            rept 0x7FFFFFFF
+                frob R0
+            endr
+            db 1
+        ", None).unwrap_err();
+
+        assert_eq!(errors.to_string(), "In -:2, invalid repeat count 2147483647");
+    }
+
+    #[test]
+    fn it_emits_words() {
         let binary = Compiler::compile_source("
-            First:
-            .loop:
-                jmp .loop
+            Table:
+                dw Table, .entry, 0x1234
+                dwl .entry, -2
+            .entry:
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x00, 0x00, 0x00, 0x0A, 0x12, 0x34, 0x0A, 0x00, 0xFE, 0xFF ]);
+    }
+
+    #[test]
+    fn it_emits_terminated_and_prefixed_strings() {
+        let binary = Compiler::compile_source("
+            asciz \"ab\"
+            pstr \"cd\"
+        ", None).expect("Failed to compile code");
 
+        assert_eq!(binary.0, vec![ 0x61, 0x62, 0x00, 0x02, 0x63, 0x64 ]);
+
+        let long = format!("pstr \"{}\"", "x".repeat(256));
+        assert!(Compiler::compile_source(&long, None).is_err());
+    }
+
+    #[test]
+    fn it_resolves_register_aliases() {
+        let binary = Compiler::compile_source("
+            def acc = R0
+            First:
+                def counter = R3
+                add acc, counter
             Second:
-            .loop:
-                jmp .loop
+                def counter = R4
+                def tmp = counter
+                add tmp, acc
         ", None).expect("Failed to compile code");
 
-        assert_eq!(binary.0, vec![ 0x20, 0x00, 0x00, 0x20, 0x00, 0x03 ]);
+        assert_eq!(binary.0, vec![ 0x10, 0x03, 0x10, 0x40 ]);
+
+        let binary = Compiler::compile_source("
+            scope S
+                def a = R1
+                inc a
+            endscope
+            inc a
+        ", None);
+
+        let err = binary.unwrap_err().diagnostics.remove(0);
+        assert_eq!(err.code, Code::Syntax);
+        assert_eq!(err.line, Some(6));
     }
 
     #[test]
-    fn string_literals_are_not_zero_terminated() {
+    fn redefining_used_alias_warns() {
+        let (_, _, warnings) = Compiler::compile_source("
+            def tmp = R1
+            inc tmp
+            def tmp = R2
+            def other = R3
+            def other = R4
+        ", None).expect("Failed to compile code");
+
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec![ "In -:4, alias 'tmp' is redefined while still in use" ]);
+    }
+
+    #[test]
+    fn it_resolves_numeric_labels() {
         let binary = Compiler::compile_source("
-            db 0xAA, \"a\", 0xBB
+            1:
+                brz 1f
+                jmp 1b
+            1:
+                jmp 1b
+                jmp 2f
+            2:
         ", None).expect("Failed to compile code");
 
-        assert_eq!(binary.0, vec![ 0xAA, 0x61, 0xBB ]);
+        assert_eq!(binary.0, vec![ 0x26, 0x00, 0x06, 0x20, 0x00, 0x00, 0x20, 0x00, 0x06, 0x20, 0x00, 0x0C ]);
+
+        let binary = Compiler::compile_source("jmp 1b", None);
+        assert_eq!(binary.unwrap_err().to_string(), "In -:1:5, no matching definition of numeric label '1'");
     }
 
     #[test]
-    fn it_produces_a_symfile() {
+    fn it_resolves_scoped_labels() {
         let binary = Compiler::compile_source("
-            org 0x0
-            A:
-            org 0x100
-            B:
-            org 0x40
-            C:
+            scope Outer
+                scope Inner
+                    loop:
+                        jmp loop
+                        jmp helper
+                endscope
+                helper:
+                    jmp Inner.loop
+            endscope
+            jmp Outer.Inner.loop
+            jmp Outer.helper
         ", None).expect("Failed to compile code");
 
-        let syms: HashMap<String, u16> = serde_json::from_str(&binary.1).expect("Failed to read symfile as json");
+        assert_eq!(binary.0, vec![ 0x20, 0x00, 0x00, 0x20, 0x00, 0x06, 0x20, 0x00, 0x00, 0x20, 0x00, 0x00, 0x20, 0x00, 0x06 ]);
+    }
+
+    #[test]
+    fn major_labels_can_be_disabled() {
+        let options = Options {
+            no_major_labels: true,
+            ..Options::default()
+        };
+
+        let binary = Compiler::compile("-", "
+            scope Routine
+                CRC_TABLE:
+                    nop
+                .loop:
+                    jmp .loop
+            endscope
+            jmp Routine.loop
+        ", options).expect("Failed to compile code");
 
-        assert_eq!(syms["A"], 0x0);
-        assert_eq!(syms["B"], 0x100);
-        assert_eq!(syms["C"], 0x40);
+        assert_eq!(binary.0, vec![ 0x00, 0x20, 0x00, 0x01, 0x20, 0x00, 0x01 ]);
     }
 
     #[test]
-    fn it_respects_whitelist() {
+    fn it_lays_out_structs() {
         let binary = Compiler::compile_source("
-            add R0, R1
-            sub R0, R1
-        ", Some(vec![ "add".to_owned() ]));
+            struct Ring
+                head: byte
+                tail: byte
+                data: ds 2 * 8
+                owner: word
+            ends
+            ldi R0, Ring.data
+            ldi R1, sizeof(Ring)
+            ds sizeof(Ring)
+            db 0xFF
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0[..6], [ 0x31, 0x00, 0x02, 0x31, 0x01, 0x14 ]);
+        assert_eq!(binary.0.len(), 6 + 0x14 + 1);
+
+        let syms: serde_json::Value = serde_json::from_str(&binary.1).expect("Failed to read symfile as json");
+
+        assert_eq!(syms["Ring"]["kind"], "struct");
+        assert_eq!(syms["Ring.owner"]["kind"], "field");
+        assert_eq!(syms["Ring.owner"]["value"], 0x12);
+    }
+
+    #[test]
+    fn it_rejects_sizeof_of_non_structs() {
+        let sources = [
+            ("Start:\nldi R0, sizeof(Start)", "'Start' is not a struct"),
+            ("struct Pair\na: byte\nb: byte\nends\nldi R0, sizeof(Pair.b)", "'Pair.b' is not a struct"),
+            ("Size equ 4\nldi R0, sizeof(Size)", "'Size' is not a struct"),
+            ("Count set 4\nldi R0, sizeof(Count)", "'Count' is not a struct"),
+            ("ldi R0, sizeof(__ASSEMBLER__)", "'__ASSEMBLER__' is not a struct"),
+            ("ldi R0, sizeof(Missing)", "undefined symbol 'Missing'"),
+        ];
+
+        for &(source, message) in sources.iter() {
+            let errors = Compiler::compile_source(source, None).expect_err("Compiled sizeof of a non-struct");
+
+            assert_eq!(errors.diagnostics[0].message, message);
+        }
+    }
+
+    #[test]
+    fn it_includes_binary_files() {
+        use util::write_to_file;
+
+        let path = TempPath::new("incbin-test.bin");
+        write_to_file(path.to_str().unwrap(), &[ 0x01, 0x02, 0x03, 0x04 ]);
+
+        let binary = Compiler::compile_source(&format!("
+            incbin \"{0}\"
+            incbin \"{0}\", 1, 2
+            incbin \"{0}\", 3
+        ", path.display()), None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x01, 0x02, 0x03, 0x04, 0x02, 0x03, 0x04 ]);
+
+        let binary = Compiler::compile_source(&format!("
+            org 0xFFFE
+            incbin \"{}\"
+        ", path.display()), None);
 
         assert!(binary.is_err());
     }
 
     #[test]
-    fn it_resolves_high_low_addr() {
+    fn it_fills_gaps() {
         let binary = Compiler::compile_source("
-            org 0x0
-            ldi R0, hi(addr)
-            ldi R1, lo(addr)
-            org 0xABBA
-            addr:
+            db 0x11
+            ds 2, 0xFF
+            align 8
+            db 0x22
+            align 4, 0xEE
+            ds 1
+            db 0x33
         ", None).expect("Failed to compile code");
 
-        assert_eq!(binary.0, vec![ 0x31, 0x00, 0xAB, 0x31, 0x01, 0xBA ]);
+        assert_eq!(binary.0, vec![
+            0x11, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0xEE, 0xEE, 0xEE, 0x00, 0x33,
+        ]);
+    }
+
+    #[test]
+    fn it_maps_characters() {
+        let binary = Compiler::compile_source("
+            db \"é☺\", 'A'
+            charmap \"ABC\", 0x80
+            charmap \"č\\u{263A}\", 1
+            db \"CAB\", 'č', \"☺\"
+            pstr \"BA\"
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0xC3, 0xA9, 0xE2, 0x98, 0xBA, 0x41, 0x82, 0x80, 0x81, 0x01, 0x02, 0x02, 0x81, 0x80 ]);
+
+        let binary = Compiler::compile_source("
+            charmap \"AB\", 0
+            db \"ABC\"
+        ", None);
+
+        assert_eq!(binary.unwrap_err().to_string(), "In -:3, character 'C' has no charmap entry");
+
+        let binary = Compiler::compile_source("db 'é'", None);
+        assert!(binary.is_err());
+    }
+
+    #[test]
+    fn it_loads_charmap_files() {
+        use util::write_to_file;
+
+        let path = TempPath::new("charmap-test.json");
+        write_to_file(path.to_str().unwrap(), b"{ \"a\": 1, \"b\": 2 }");
+
+        let binary = Compiler::compile_source(&format!("
+            charmap \"{}\"
+            db \"ab\", 'b'
+        ", path.display()), None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x01, 0x02, 0x02 ]);
+    }
+
+    #[test]
+    fn it_expands_pseudo_instructions() {
+        let binary = Compiler::compile_source("
+            clr R1
+            tst R2
+            ldi16 R3, R4, 0xABCD
+            bra 0x1234
+            jz 0x5678
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![
+            0x18, 0x11, 0x17, 0x22, 0x31, 0x03, 0xAB, 0x31, 0x04, 0xCD,
+            0x20, 0x12, 0x34, 0x26, 0x56, 0x78,
+        ]);
+
+        let binary = Compiler::compile_source("clr R0", Some(vec![ "or".to_owned() ]));
+        assert_eq!(binary.unwrap_err().to_string(), "In -:1, use of instruction 'xor' not allowed with current whitelist");
+    }
+
+    #[test]
+    fn it_places_sections() {
+        let (binary, _, _) = Compiler::compile_source("
+            section code
+            Main:
+                jmp Loop
+            section data
+            Counter:
+                db 0x11
+            section code
+            Loop:
+                ldi R0, lo(Counter)
+            section vectors, 0x10
+                dw Loop
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary, vec![
+            0x20, 0x00, 0x03, 0x31, 0x00, 0x06, 0x11, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x03,
+        ]);
+
+        let result = Compiler::compile_source("
+            section data, 0x02
+                db 1
+            section code
+                db 2, 3, 4
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "Sections 'code' and 'data' overlap at address 0x0002");
+    }
+
+    #[test]
+    fn it_reserves_space() {
+        let (binary, symbols, _) = Compiler::compile_source("
+            section code
+                ldi R0, lo(Flags)
+            section bss
+            Buffer:
+                res 4
+            Flags:
+                res 1
+            section data
+                db 0x11
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(binary, vec![ 0x31, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11 ]);
+
+        let syms: serde_json::Value = serde_json::from_str(&symbols).expect("Failed to read symfile as json");
+        assert_eq!(syms["Buffer"]["value"], 0x03);
+    }
+
+    #[test]
+    fn it_stops_at_the_end_of_memory() {
+        let result = Compiler::compile_source("
+            org 0xFFFE
+            dw 0x1234
+        ", None);
+        assert!(result.is_ok());
+
+        let result = Compiler::compile_source("
+            org 0xFFFE
+            jmp 0
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "In -:3, output would run past address 0xFFFF");
+
+        let result = Compiler::compile_source("
+            org 0xFFFF
+            nop
+            nop
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "In -:4, output would run past address 0xFFFF");
+    }
+
+    #[test]
+    fn it_checks_assertions() {
+        let result = Compiler::compile_source("
+            Start:
+                assert End - Start <= 2, \"routine too long\"
+                nop
+            End:
+        ", None);
+        assert!(result.is_ok());
+
+        let result = Compiler::compile_source("
+            Start:
+                assert End - Start <= 2, \"routine too long\"
+                nop
+                nop
+                nop
+            End:
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "In -:3, assertion failed: routine too long");
+
+        let result = Compiler::compile_source("
+            if 1
+                error \"unsupported configuration\"
+            endif
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "In -:3, unsupported configuration");
+    }
+
+    #[test]
+    fn it_searches_include_paths() {
+        use std::fs;
+        use util::write_to_file;
+
+        let root = TempPath::new("include-test");
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        fs::create_dir_all(root.join("inc")).unwrap();
+        fs::create_dir_all(root.join("src/lib")).unwrap();
+
+        write_to_file(root.join("src/sub/a.asm").to_str().unwrap(), b"db 1\ninclude \"b.asm\"");
+        write_to_file(root.join("src/sub/b.asm").to_str().unwrap(), b"db 2");
+        write_to_file(root.join("inc/c.asm").to_str().unwrap(), b"db 3");
+        write_to_file(root.join("src/lib/d.asm").to_str().unwrap(), b"db 4");
+        write_to_file(root.join("inc/e.bin").to_str().unwrap(), &[ 0x05 ]);
+        write_to_file(root.join("src/lib/f.json").to_str().unwrap(), b"{ \"f\": 6 }");
+
+        let main = root.join("src/main.asm");
+        let options = Options {
+            include_paths: vec![ root.join("inc").to_str().unwrap().to_owned() ],
+            ..Options::default()
+        };
+        let source = "
+            include \"sub/a.asm\"
+            include \"c.asm\"
+            include \"d.asm\"
+            incbin \"e.bin\"
+            charmap \"f.json\"
+            db \"f\"
+        ";
+        let binary = Compiler::compile(main.to_str().unwrap(), source, options).expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x01, 0x02, 0x03, 0x04, 0x05, 0x06 ]);
+
+        for directive in [ "include", "incbin", "charmap" ].iter() {
+            let result = Compiler::compile(main.to_str().unwrap(), &format!("{} \"e.asm\"", directive), Options::default());
+            assert_eq!(result.unwrap_err().to_string(), format!(
+                "In {}:1, file 'e.asm' not found, tried '{}', '{}'",
+                main.display(), root.join("src/e.asm").display(), root.join("src/lib/e.asm").display(),
+            ));
+        }
+    }
+
+    #[test]
+    fn it_rejects_recursive_includes() {
+        use std::fs;
+        use util::write_to_file;
+
+        let root = TempPath::new("recursive-test");
+        fs::create_dir_all(root.join("d")).unwrap();
+        let path = root.join("d/r.asm");
+        write_to_file(path.to_str().unwrap(), b"db 1\ninclude \"../d/r.asm\"");
+
+        let result = Compiler::compile_source(&format!("include \"{}\"", path.display()), None);
+        assert_eq!(result.unwrap_err().to_string(), format!(
+            "In {}:2, file '{}' is included recursively\n    included from -:1",
+            path.display(), root.join("d/../d/r.asm").display(),
+        ));
+    }
+
+    #[test]
+    fn it_includes_files_once() {
+        use util::write_to_file;
+
+        let a = TempPath::new("once-test-a.asm");
+        let b = TempPath::new("once-test-b.asm");
+        write_to_file(a.to_str().unwrap(), b"db 1");
+        write_to_file(b.to_str().unwrap(), b"pragma once\ndb 2");
+
+        let binary = Compiler::compile_source(&format!("
+            include \"{0}\"
+            include_once \"{0}\"
+            include \"{1}\"
+            include \"{1}\"
+        ", a.display(), b.display()), None)
+            .expect("Failed to compile code");
+
+        assert_eq!(binary.0, vec![ 0x01, 0x02 ]);
+    }
+
+    #[test]
+    fn it_predefines_symbols() {
+        let source = "
+            ifdef DEBUG
+                db LEVEL
+            endif
+            ifdef __ASSEMBLER__
+                db 0xAA
+            endif
+        ";
+
+        let options = Options {
+            defines: vec![ "DEBUG".to_owned(), "LEVEL=0x10".to_owned() ],
+            undefines: vec![ "__ASSEMBLER__".to_owned() ],
+            ..Options::default()
+        };
+        let binary = Compiler::compile("-", source, options).expect("Failed to compile code");
+        assert_eq!(binary.0, vec![ 0x10 ]);
+
+        let binary = Compiler::compile("-", source, Options::default()).expect("Failed to compile code");
+        assert_eq!(binary.0, vec![ 0xAA ]);
+
+        let options = Options {
+            defines: vec![ "LEVEL=high".to_owned() ],
+            ..Options::default()
+        };
+        let result = Compiler::compile("-", source, options);
+        assert_eq!(result.unwrap_err().to_string(), "Invalid value 'high' in definition 'LEVEL=high'");
+    }
+
+    #[test]
+    fn it_resolves_location_counter() {
+        let binary = Compiler::compile_source("
+            org 0x10
+            Msg:
+                db \"abc\"
+            len equ $ - Msg
+                db len, lo($)
+                jmp $
+            section data
+                dw $ + 1
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(&binary.0[0x10..], &[ 0x61, 0x62, 0x63, 0x03, 0x13, 0x20, 0x00, 0x15, 0x00, 0x19 ]);
+    }
+
+    #[test]
+    fn it_assembles_phased_code() {
+        let (binary, symbols, _) = Compiler::compile_source("
+            org 0x10
+            phase 0x8000
+            Routine:
+                jmp Routine
+                dw $
+            dephase
+            After:
+                jmp After
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(&binary[0x10..], &[ 0x20, 0x80, 0x00, 0x80, 0x03, 0x20, 0x00, 0x15 ]);
+
+        let syms: serde_json::Value = serde_json::from_str(&symbols).expect("Failed to read symfile as json");
+        assert_eq!(syms["Routine"]["value"], 0x8000);
+        assert_eq!(syms["Routine"]["load"], 0x10);
+        assert_eq!(syms["After"]["value"], 0x15);
+    }
+
+    #[test]
+    fn it_writes_vectors() {
+        let binary = Compiler::compile_source("
+            vector interrupt, Handler
+            org 0x10
+            Handler:
+                reti
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(&binary.0[0..6], &[ 0x00, 0x00, 0x00, 0x20, 0x00, 0x10 ]);
+
+        let binary = Compiler::compile_source("
+            vector default, Spurious
+            vector reset, Main
+            org 0x10
+            Main:
+                nop
+            Spurious:
+                reti
+        ", None).expect("Failed to compile code");
+
+        assert_eq!(&binary.0[0..6], &[ 0x20, 0x00, 0x10, 0x20, 0x00, 0x11 ]);
+
+        let result = Compiler::compile_source("
+            vector reset, 0x10
+            vector reset, 0x20
+        ", None);
+        assert_eq!(result.unwrap_err().to_string(), "In -:3, vector 'reset' is already assigned in -:2");
+
+        let result = Compiler::compile_source("vector reset, 0x10", Some(vec![ "nop".to_owned() ]));
+        assert_eq!(result.unwrap_err().to_string(), "In -:1, use of instruction 'jmp' not allowed with current whitelist");
+    }
+
+    #[test]
+    fn diagnostics_point_at_their_source() {
+        use util::write_to_file;
+
+        let path = TempPath::new("diagnostic-test.asm");
+        write_to_file(path.to_str().unwrap(), b"nop\njmp Missing");
+
+        let err = Compiler::compile_source(&format!("\n    include \"{}\"", path.display()), None).unwrap_err().diagnostics.remove(0);
+        assert_eq!(err.severity, Severity::Error);
+        assert_eq!(err.code, Code::UndefinedSymbol);
+        assert_eq!(err.file, Some(path.to_str().unwrap().to_owned()));
+        assert_eq!(err.line, Some(2));
+        assert_eq!(err.columns, Some(5..12));
+        assert_eq!(err.include_chain, vec![ ("-".to_owned(), 2) ]);
+
+        let err = Compiler::compile_source("ldi R0 5", None).unwrap_err().diagnostics.remove(0);
+        assert_eq!(err.code, Code::Syntax);
+        assert_eq!(err.columns, Some(7..8));
+
+        let err = Compiler::compile_source("ldi R0, 1 + Missing * 2", None).unwrap_err().diagnostics.remove(0);
+        assert_eq!(err.columns, Some(13..20));
+
+        let err = Compiler::compile_source("ldi R0, 0x80 * 2 ; too big", None).unwrap_err().diagnostics.remove(0);
+        assert_eq!(err.code, Code::OutOfRange);
+        assert_eq!(err.columns, Some(9..17));
+    }
+
+    #[test]
+    fn it_reports_all_errors() {
+        use std::fs;
+        use util::write_to_file;
+
+        let source = "
+            ldi R0 1
+            jmp Missing
+            ldi R1, 300
+            frob R2
+        ";
+
+        let errors = Compiler::compile_source(source, None).unwrap_err();
+        assert_eq!(errors.to_string(), "\
+            In -:2:19, expected a comma\n\
+            In -:3:17, undefined symbol 'Missing'\n\
+            In -:4:21, value 300 is out of range for a byte operand\n\
+            In -:5, unknown instruction or macro 'frob'");
+        assert!(!errors.truncated);
+
+        let root = TempPath::new("order-test");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        write_to_file(root.join("sub/zz.asm").to_str().unwrap(), b"jmp InZz");
+        write_to_file(root.join("aa.asm").to_str().unwrap(), b"frob R0");
+
+        let main = "
+            include \"sub/zz.asm\"
+            macro broken
+                jmp InMacro
+            endm
+            include \"aa.asm\"
+            ldi R0, 300
+            broken
+        ";
+        let errors = Compiler::compile(root.join("main.asm").to_str().unwrap(), main, Options::default()).unwrap_err();
+        let messages = errors.diagnostics.iter().map(|d| d.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            "undefined symbol 'InZz'",
+            "unknown instruction or macro 'frob'",
+            "value 300 is out of range for a byte operand",
+            "undefined symbol 'InMacro'",
+        ]);
+
+        let options = Options {
+            max_errors: Some(2),
+            ..Options::default()
+        };
+        let errors = Compiler::compile("-", source, options).unwrap_err();
+        assert_eq!(errors.diagnostics.len(), 2);
+        assert!(errors.truncated);
     }
 }
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::ops::{Deref,DerefMut,Range};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Kind of a diagnostic, stable across changes to the wording of its message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    Syntax,
+    UndefinedSymbol,
+    Redefinition,
+    OutOfRange,
+    Evaluation,
+    Encoding,
+    Whitelist,
+    File,
+    UnbalancedBlock,
+    InvalidUse,
+    Nesting,
+    Overlap,
+    Assertion,
+    User,
+    InvalidOption,
+    AliasInUse,
+    UserWarning,
+}
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::Syntax => "E001",
+            Code::UndefinedSymbol => "E002",
+            Code::Redefinition => "E003",
+            Code::OutOfRange => "E004",
+            Code::Evaluation => "E005",
+            Code::Encoding => "E006",
+            Code::Whitelist => "E007",
+            Code::File => "E008",
+            Code::UnbalancedBlock => "E009",
+            Code::InvalidUse => "E010",
+            Code::Nesting => "E011",
+            Code::Overlap => "E012",
+            Code::Assertion => "E013",
+            Code::User => "E014",
+            Code::InvalidOption => "E015",
+            Code::AliasInUse => "W001",
+            Code::UserWarning => "W002",
+        }
+    }
+}
+
+/// An error or warning, pointing at the source line it concerns when there is one.
+///
+/// The details are boxed to keep the `Result`s that carry diagnostics small.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic(Box<Details>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Details {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    /// Position of the line in the order lines were read, used to list diagnostics in source order.
+    pub index: Option<usize>,
+    /// Columns of the line the diagnostic refers to, starting at 1.
+    pub columns: Option<Range<usize>>,
+    /// Macro invocations that produced the line, innermost first, as name, file and line.
+    pub expansions: Vec<(String, String, usize)>,
+    /// Lines of `include` directives through which the file was reached, innermost first.
+    pub include_chain: Vec<(String, usize)>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: Code, message: String) -> Self {
+        Self::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code: Code, message: String) -> Self {
+        Self::new(Severity::Warning, code, message)
+    }
+
+    fn new(severity: Severity, code: Code, message: String) -> Self {
+        Diagnostic(Box::new(Details {
+            severity,
+            code,
+            message,
+            file: None,
+            line: None,
+            index: None,
+            columns: None,
+            expansions: Vec::new(),
+            include_chain: Vec::new(),
+            notes: Vec::new(),
+        }))
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// Points the diagnostic at `columns`, unless it already points at a part of the line.
+    pub fn with_columns(mut self, columns: Option<Range<usize>>) -> Self {
+        if self.columns.is_none() {
+            self.columns = columns;
+        }
+        self
+    }
+}
+
+impl Deref for Diagnostic {
+    type Target = Details;
+
+    fn deref(&self) -> &Details {
+        &self.0
+    }
+}
+
+impl DerefMut for Diagnostic {
+    fn deref_mut(&mut self) -> &mut Details {
+        &mut self.0
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(ref file), Some(line)) = (&self.file, self.line) {
+            write!(f, "In {}:{}", file, line)?;
+            if let Some(ref columns) = self.columns {
+                write!(f, ":{}", columns.start)?;
+            }
+            for &(ref name, ref file, line) in self.expansions.iter() {
+                write!(f, " (in '{}' invoked at {}:{}", name, file, line)?;
+            }
+            for _ in self.expansions.iter() {
+                write!(f, ")")?;
+            }
+            write!(f, ", ")?;
+        }
+
+        write!(f, "{}", self.message)?;
+
+        for &(ref file, line) in self.include_chain.iter() {
+            write!(f, "\n    included from {}:{}", file, line)?;
+        }
+        for note in self.notes.iter() {
+            write!(f, "\n    note: {}", note)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// All errors of a failed compilation.
+#[derive(Debug)]
+pub struct Errors {
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether assembly stopped early because the error limit was reached.
+    pub truncated: bool,
+    /// Warnings reported before assembly failed.
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl From<Diagnostic> for Errors {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self {
+            diagnostics: vec![ diagnostic ],
+            truncated: false,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
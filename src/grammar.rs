@@ -1,8 +1,10 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap,HashSet};
+use std::ops::Range;
 
 pub type Label = String;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Register(pub u8);
 
 impl Register {
@@ -13,46 +15,280 @@ impl Register {
             Err("register index between 0 and 15")
         }
     }
+
+    /// Parses a plain register name, such as `R12`.
+    pub fn parse(name: &str) -> Option<Register> {
+        if name.len() < 2 || !(name.starts_with("R") || name.starts_with("r")) {
+            return None;
+        }
+        name[1..].parse::<u8>().ok().and_then(|n| Register::new(n).ok())
+    }
 }
 
-#[derive(Debug)]
-pub enum Address {
-    Label(Label),
-    Immediate(u16),
+/// Register aliases defined with `def`, either globally or for the current major label.
+#[derive(Debug, Default)]
+pub struct Aliases {
+    global: HashMap<String, u8>,
+    local: HashMap<String, u8>,
+    /// Aliases resolved by the parser since their definition.
+    used: RefCell<HashSet<String>>,
 }
 
-pub type Opcode = u8;
+impl Aliases {
+    pub fn get(&self, name: &str) -> Option<Register> {
+        let register = self.local.get(name).or_else(|| self.global.get(name))?;
+        self.used.borrow_mut().insert(name.to_owned());
+        Some(Register(*register))
+    }
 
-#[derive(Debug)]
-pub enum Serializable {
-    Byte(u8),
-    String(String),
+    /// Defines an alias, returning whether it replaced one that was in use in the same scope.
+    pub fn define(&mut self, name: &str, register: Register, global: bool) -> bool {
+        let scope = if global { &mut self.global } else { &mut self.local };
+        let replaced = scope.insert(name.to_owned(), register.0).is_some();
+        let used = self.used.borrow_mut().remove(name);
+        replaced && used
+    }
+
+    pub fn leave_scope(&mut self) {
+        for name in self.local.drain() {
+            self.used.borrow_mut().remove(&name.0);
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum Nibble {
-    Both,
+#[derive(Debug, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
     High,
     Low,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Label(Label),
+    /// A label looked up in each of the enclosing scopes in turn, innermost first.
+    Scoped(Vec<Label>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// Size of the struct named by the inner label.
+    Sizeof(Box<Expr>),
+    /// An expression along with the columns of the line it was parsed from, starting at 1.
+    Spanned(Range<usize>, Box<Expr>),
+}
+
+impl Expr {
+    fn unary(op: UnaryOp, e: Expr) -> Expr {
+        Expr::Unary(op, Box::new(e))
+    }
+
+    fn binary(op: BinaryOp, l: Expr, r: Expr) -> Expr {
+        Expr::Binary(op, Box::new(l), Box::new(r))
+    }
+
+    fn spanned(start: usize, end: usize, e: Expr) -> Expr {
+        Expr::Spanned(start + 1 .. end + 1, Box::new(e))
+    }
+
+    /// Columns the expression was parsed from, if known.
+    pub fn columns(&self) -> Option<Range<usize>> {
+        match self {
+            Expr::Spanned(columns, _) => Some(columns.clone()),
+            _ => None,
+        }
+    }
+
+    /// Rebuilds the expression, replacing every label with the result of `f`, which is also
+    /// told whether the label is the operand of `sizeof`.
+    pub fn map_labels<F>(self, f: &mut F) -> Expr where F: FnMut(Label, bool) -> Expr {
+        self.map_labels_nested(f, false)
+    }
+
+    fn map_labels_nested<F>(self, f: &mut F, in_sizeof: bool) -> Expr where F: FnMut(Label, bool) -> Expr {
+        match self {
+            Expr::Number(n) => Expr::Number(n),
+            Expr::Label(l) => f(l, in_sizeof),
+            Expr::Scoped(ls) => Expr::Scoped(ls),
+            Expr::Unary(op, e) => Expr::unary(op, e.map_labels_nested(f, in_sizeof)),
+            Expr::Binary(op, l, r) => {
+                let l = l.map_labels_nested(f, in_sizeof);
+                Expr::binary(op, l, r.map_labels_nested(f, in_sizeof))
+            },
+            Expr::Sizeof(e) => Expr::Sizeof(Box::new(e.map_labels_nested(f, true))),
+            Expr::Spanned(columns, e) => Expr::Spanned(columns, Box::new(e.map_labels_nested(f, in_sizeof))),
+        }
+    }
+}
+
+impl UnaryOp {
+    pub fn apply(&self, x: i64) -> i64 {
+        match self {
+            UnaryOp::Neg => x.wrapping_neg(),
+            UnaryOp::Not => !x,
+            UnaryOp::LogicalNot => (x == 0) as i64,
+            UnaryOp::High => (x >> 8) & 0xFF,
+            UnaryOp::Low => x & 0xFF,
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn apply(&self, x: i64, y: i64) -> Result<i64, String> {
+        let result = match self {
+            BinaryOp::Add => x.checked_add(y),
+            BinaryOp::Sub => x.checked_sub(y),
+            BinaryOp::Mul => x.checked_mul(y),
+            BinaryOp::Div => {
+                if y == 0 {
+                    return Err("Division by zero".to_owned());
+                }
+                x.checked_div(y)
+            },
+            BinaryOp::Rem => {
+                if y == 0 {
+                    return Err("Division by zero".to_owned());
+                }
+                x.checked_rem(y)
+            },
+            BinaryOp::Shl => if (0..64).contains(&y) { Some(x << y) } else { None },
+            BinaryOp::Shr => if (0..64).contains(&y) { Some(x >> y) } else { None },
+            BinaryOp::And => Some(x & y),
+            BinaryOp::Or => Some(x | y),
+            BinaryOp::Xor => Some(x ^ y),
+            BinaryOp::Eq => Some((x == y) as i64),
+            BinaryOp::Ne => Some((x != y) as i64),
+            BinaryOp::Lt => Some((x < y) as i64),
+            BinaryOp::Le => Some((x <= y) as i64),
+            BinaryOp::Gt => Some((x > y) as i64),
+            BinaryOp::Ge => Some((x >= y) as i64),
+            BinaryOp::LogicalAnd => Some((x != 0 && y != 0) as i64),
+            BinaryOp::LogicalOr => Some((x != 0 || y != 0) as i64),
+        };
+
+        result.ok_or_else(|| "Arithmetic overflow in expression".to_owned())
+    }
+}
+
+/// Encoding of characters in string and character literals, set up with `charmap`.
+#[derive(Debug, Default)]
+pub struct Charmap {
+    map: HashMap<char, u8>,
+}
+
+impl Charmap {
+    /// Encodes a character literal. Until the first mapping is defined, only ASCII characters,
+    /// which are a single byte in UTF-8, can be encoded.
+    pub fn get(&self, c: char) -> Option<u8> {
+        if self.map.is_empty() {
+            if c.is_ascii() { Some(c as u8) } else { None }
+        } else {
+            self.map.get(&c).cloned()
+        }
+    }
+
+    /// Encodes a string as UTF-8 until the first mapping is defined, failing with the first
+    /// unmapped character afterwards.
+    pub fn encode(&self, s: &str) -> Result<Vec<u8>, char> {
+        if self.map.is_empty() {
+            return Ok(s.as_bytes().to_vec());
+        }
+
+        s.chars().map(|c| self.map.get(&c).cloned().ok_or(c)).collect()
+    }
+
+    pub fn insert(&mut self, c: char, byte: u8) {
+        self.map.insert(c, byte);
+    }
+}
+
+pub type Opcode = u8;
+
 #[derive(Debug)]
-pub enum Value {
-    Immediate(u8),
-    Addr(Address, Nibble),
+pub enum Serializable {
+    Byte(Expr),
+    String(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Endianness {
+    Big,
+    Little,
 }
 
 #[derive(Debug)]
 pub enum Instruction {
     Db(Vec<Serializable>),
-    Ds(u16),
-    Org(u16),
-    Include(String),
+    Dw(Vec<Expr>, Endianness),
+    Def(String, Register),
+    Scope(Label),
+    Endscope,
+    Struct(Label),
+    Ends,
+    Byte,
+    Word,
+    Asciz(String),
+    Pstr(String),
+    Ds(Expr, Option<Expr>),
+    Res(Expr),
+    Align(Expr, Option<Expr>),
+    Org(Expr),
+    Assert(Expr, Option<String>),
+    Error(String),
+    Warning(String),
+    Section(String, Option<Expr>),
+    Phase(Expr),
+    Dephase,
+    /// Name of an interrupt or reset vector and the address of its handler.
+    Vector(String, Expr),
+    /// Path of the included file and whether to skip it if it was already included.
+    Include(String, bool),
+    PragmaOnce,
+    Incbin(String, Option<Expr>, Option<Expr>),
+    Charmap(String, Option<Expr>),
+    Equ(Label, Expr),
+    Set(Label, Expr),
+    If(Expr),
+    Ifdef(Label),
+    Ifndef(Label),
+    Elif(Expr),
+    Else,
+    Endif,
+    Macro(String, Vec<String>),
+    Endm,
+    MacroCall(String, Vec<String>),
+    Rept(Expr, Option<Label>),
+    Endr,
     Nullary(Opcode),
     UnaryReg(Opcode, Register),
-    UnaryAddr(Opcode, Address),
-    BinaryRegIm(Opcode, Register, Value),
+    UnaryAddr(Opcode, Expr),
+    BinaryRegIm(Opcode, Register, Expr),
     BinaryRegReg(Opcode, Register, Register),
+    /// A pseudo-instruction, expanded to the real instructions it stands for.
+    Pseudo(Vec<Instruction>),
 }
 
 impl Instruction {
@@ -60,7 +296,14 @@ impl Instruction {
         use self::Instruction::*;
 
         match self {
-            Db(_) | Ds(_) | Org(_) | Include(_) => None,
+            Db(_) | Dw(_, _) | Asciz(_) | Pstr(_) | Ds(_, _) | Res(_) | Align(_, _) | Org(_) | Section(_, _) | Phase(_) | Dephase
+            | Assert(_, _) | Error(_) | Warning(_)
+            | Include(_, _) | PragmaOnce | Incbin(_, _, _) | Charmap(_, _)
+            | Equ(_, _) | Set(_, _) | Def(_, _) | Scope(_) | Endscope
+            | Struct(_) | Ends | Byte | Word
+            | If(_) | Ifdef(_) | Ifndef(_) | Elif(_) | Else | Endif
+            | Macro(_, _) | Endm | MacroCall(_, _) | Rept(_, _) | Endr
+            | Pseudo(_) | Vector(_, _) => None,
             Nullary(op)
             | UnaryReg(op, _)
             | UnaryAddr(op, _)
@@ -76,6 +319,27 @@ pub struct Line {
     pub instruction: Option<Instruction>,
 }
 
+/// Name of the location counter symbol, which can't clash with a label.
+pub const HERE: &str = "$";
+
+/// Names of the assembler directives, which can't be used as macro names.
+pub const DIRECTIVES: &[&str] = &[
+    "db", "dw", "dwb", "dwl", "asciz", "pstr", "ds", "align", "org", "phase", "dephase", "section", "vector", "include", "include_once", "pragma", "incbin", "charmap",
+    "equ", "set", "def", "scope", "endscope", "struct", "ends", "byte", "word",
+    "assert", "error", "warning",
+    "if", "ifdef", "ifndef", "elif", "else", "endif", "macro", "endm", "rept", "endr",
+];
+
+/// Names of the pseudo-instructions provided by the assembler.
+pub const PSEUDO_INSTRUCTIONS: &[&str] = &[
+    "clr", "tst", "ldi16", "bra", "jz", "jnz", "jc", "jnc",
+];
+
+/// Checks whether `name` is an instruction mnemonic or a directive.
+pub fn is_reserved(name: &str) -> bool {
+    OPCODES.contains_key(name) || DIRECTIVES.contains(&name) || PSEUDO_INSTRUCTIONS.contains(&name)
+}
+
 lazy_static! {
     pub static ref OPCODES: HashMap<&'static str, Opcode> = {
         let mut map = HashMap::new();
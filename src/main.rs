@@ -3,15 +3,20 @@ extern crate lazy_static;
 extern crate clap;
 
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 
 mod grammar;
 mod compiler;
+mod diagnostic;
 mod util;
+#[cfg(test)]
+mod temp_path;
 
 use clap::{App,Arg};
 
 use compiler::*;
+use diagnostic::Diagnostic;
 use util::{read_to_string,write_to_file};
 
 fn main() {
@@ -43,6 +48,49 @@ fn main() {
             .help("If set, path to a file containing instruction whitelist")
             .required(false)
             .takes_value(true))
+        .arg(Arg::with_name("include")
+            .value_name("DIR")
+            .short("I")
+            .long("include")
+            .help("Adds a directory to search for included files")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("vectors")
+            .value_name("FILE")
+            .long("vectors")
+            .help("If set, path to a file containing the vector table of the target CPU")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("define")
+            .value_name("NAME[=VALUE]")
+            .short("D")
+            .long("define")
+            .help("Defines a symbol before assembling, with the value 1 unless given")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("undefine")
+            .value_name("NAME")
+            .short("U")
+            .long("undefine")
+            .help("Removes a predefined symbol before assembling")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("max-errors")
+            .value_name("COUNT")
+            .long("max-errors")
+            .help("Stops assembling after this many errors, or never if 0")
+            .required(false)
+            .takes_value(true)
+            .default_value("20"))
+        .arg(Arg::with_name("no-major-labels")
+            .long("no-major-labels")
+            .help("Don't start a new scope at labels beginning with an uppercase letter"))
         .get_matches();
 
     let filename = matches.value_of("file").expect("File name was not provided");
@@ -58,16 +106,73 @@ fn main() {
                     })
             });
 
-    match Compiler::compile_file(filename, whitelist) {
-        Ok((binary, symbols)) => {
+    let vectors: Option<Vec<(String, u16)>> =
+        matches.value_of("vectors")
+            .map(read_to_string)
+            .map(|ref s| {
+                serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(s)
+                    .map_err(|err| err.to_string())
+                    .and_then(|table| {
+                        table.into_iter()
+                            .map(|(name, address)| match address.as_u64() {
+                                Some(address) if address <= 0xFFFD => Ok((name, address as u16)),
+                                _ => Err(format!("invalid address of vector '{}'", name)),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_else(|err| {
+                        eprintln!("Failed to parse vector table: {}.", err);
+                        std::process::exit(1);
+                    })
+            });
+
+    let max_errors: usize = matches.value_of("max-errors").unwrap()
+        .parse()
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to parse error limit: {}.", err);
+            std::process::exit(1);
+        });
+
+    let options = Options {
+        whitelist,
+        no_major_labels: matches.is_present("no-major-labels"),
+        include_paths: matches.values_of("include")
+            .map(|dirs| dirs.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        defines: matches.values_of("define")
+            .map(|defines| defines.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        undefines: matches.values_of("undefine")
+            .map(|names| names.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        vectors,
+        max_errors: if max_errors == 0 { None } else { Some(max_errors) },
+    };
+
+    match Compiler::compile_file(filename, options) {
+        Ok((binary, symbols, warnings)) => {
+            print_warnings(&warnings);
             write_to_file(matches.value_of("output").unwrap_or("out.bin"), &binary);
 
             if let Some(symfilepath) = matches.value_of("symfile") {
                 write_to_file(symfilepath, symbols.as_bytes());
             }
         },
-        Err(err) => {
-            println!("Error: {}", err);
+        Err(errors) => {
+            print_warnings(&errors.warnings);
+            for err in errors.diagnostics.iter() {
+                println!("Error[{}]: {}", err.code.as_str(), err);
+            }
+            if errors.truncated {
+                println!("Stopped after {} errors.", errors.diagnostics.len());
+            }
+            std::process::exit(1);
         }
     }
 }
+
+fn print_warnings(warnings: &[Diagnostic]) {
+    for warning in warnings {
+        eprintln!("Warning[{}]: {}", warning.code.as_str(), warning);
+    }
+}
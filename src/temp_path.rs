@@ -0,0 +1,40 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path,PathBuf};
+use std::process;
+
+/// A path in the temporary directory, unique to the running process, which is removed
+/// along with anything written to it when dropped.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        TempPath(env::temp_dir().join(format!("assembler-{}-{}", process::id(), name)))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+        else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
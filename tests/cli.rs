@@ -1,8 +1,12 @@
 extern crate assert_cli;
 
+#[path = "../src/temp_path.rs"]
+mod temp_path;
+
 #[cfg(test)]
 mod integration {
     use assert_cli;
+    use temp_path::TempPath;
 
     #[test]
     fn without_args() {
@@ -41,4 +45,19 @@ mod integration {
             .stderr().contains("No such file or directory")
             .unwrap();
     }
+
+    #[test]
+    fn reports_all_errors() {
+        use std::fs;
+
+        let path = TempPath::new("cli-errors.asm");
+        fs::write(&path, "jmp Missing\nfrob R0\n").unwrap();
+
+        assert_cli::Assert::main_binary()
+            .with_args(&[ path.to_str().unwrap() ])
+            .fails()
+            .stdout().contains("unknown instruction or macro 'frob'")
+            .stdout().contains("undefined symbol 'Missing'")
+            .unwrap();
+    }
 }